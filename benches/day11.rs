@@ -0,0 +1,72 @@
+use advent_of_code_2020::day11::{SeatLayout, Seating};
+use std::hint::black_box;
+use std::time::Instant;
+
+const SIZE: usize = 1000;
+
+fn bench<T>(name: &str, iterations: u32, mut f: impl FnMut() -> T) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    println!("{:<32} {:>12.3?}/iter", name, start.elapsed() / iterations);
+}
+
+/// A `SIZE` by `SIZE` layout with roughly one cell in five floor, from a
+/// fixed linear congruential generator so every run sees the same layout.
+fn layout() -> SeatLayout {
+    let mut state: u64 = 2020;
+    let mut text = String::with_capacity((SIZE + 1) * SIZE);
+    for _ in 0..SIZE {
+        for _ in 0..SIZE {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            text.push(if (state >> 33) % 5 == 0 { '.' } else { 'L' });
+        }
+        text.push('\n');
+    }
+    text.parse().unwrap()
+}
+
+fn main() {
+    let layout = layout();
+    let adjacent = layout.adjacency();
+    let visible = layout.line_of_sight();
+
+    bench("adjacency", 3, || layout.adjacency());
+    bench("line of sight", 3, || layout.line_of_sight());
+
+    let mut lay = layout.clone();
+    bench("adjacent step", 10, || {
+        lay.step(&adjacent, &Seating { tolerance: 4 })
+    });
+    let mut lay = layout.clone();
+    bench("visible step", 10, || {
+        lay.step(&visible, &Seating { tolerance: 5 })
+    });
+    let mut lay = layout.clone();
+    bench("adjacent update", 3, || {
+        lay.update(4, SeatLayout::adjacent_occupied)
+    });
+
+    #[cfg(feature = "parallel")]
+    {
+        let mut lay = layout.clone();
+        bench("adjacent parallel step", 10, || {
+            lay.par_step(&adjacent, &Seating { tolerance: 4 })
+        });
+        let mut lay = layout.clone();
+        bench("visible parallel step", 10, || {
+            lay.par_step(&visible, &Seating { tolerance: 5 })
+        });
+    }
+
+    bench("adjacent 100 generations", 1, || {
+        let mut lay = layout.clone();
+        for _ in 0..100 {
+            lay.step(&adjacent, &Seating { tolerance: 4 });
+        }
+        lay.occupied_seats()
+    });
+}
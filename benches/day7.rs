@@ -0,0 +1,75 @@
+use advent_of_code_2020::day7::{Bag, BagColor, BagRules, Mode};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const COLORS: usize = 100_000;
+
+fn bench<T>(name: &str, iterations: u32, mut f: impl FnMut() -> T) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    println!("{:<32} {:>12.3?}/iter", name, start.elapsed() / iterations);
+}
+
+/// Like `bench`, timing only `f` and giving it a fresh value from `setup` on
+/// every iteration, so results cached by a previous iteration are not reused.
+fn bench_fresh<S, T>(
+    name: &str,
+    iterations: u32,
+    mut setup: impl FnMut() -> S,
+    mut f: impl FnMut(&S) -> T,
+) {
+    let mut elapsed = Duration::default();
+    for _ in 0..iterations {
+        let input = setup();
+        let start = Instant::now();
+        black_box(f(&input));
+        elapsed += start.elapsed();
+    }
+    println!("{:<32} {:>12.3?}/iter", name, elapsed / iterations);
+}
+
+fn color(i: usize) -> BagColor {
+    BagColor::new("synthetic", &i.to_string())
+}
+
+/// Every bag holds the next one, `COLORS` levels deep.
+fn chain() -> Vec<Bag> {
+    (0..COLORS)
+        .map(|i| Bag {
+            color: color(i),
+            contents: (i + 1..COLORS).take(1).map(|c| (color(c), 1)).collect(),
+        })
+        .collect()
+}
+
+/// Every bag holds two others, forming a balanced binary tree.
+fn tree() -> Vec<Bag> {
+    (0..COLORS)
+        .map(|i| Bag {
+            color: color(i),
+            contents: (2 * i + 1..COLORS).take(2).map(|c| (color(c), 2)).collect(),
+        })
+        .collect()
+}
+
+fn main() {
+    for (name, bags) in [("chain", chain()), ("tree", tree())].iter() {
+        let rules = || BagRules::new(bags, Mode::Strict).unwrap();
+
+        bench(&format!("{} build", name), 10, rules);
+        bench_fresh(&format!("{} contents of root", name), 10, rules, |rules| {
+            rules.contents(&color(0))
+        });
+        bench_fresh(
+            &format!("{} contents of midpoint", name),
+            10,
+            rules,
+            |rules| rules.contents(&color(COLORS / 2 - 1)),
+        );
+        bench_fresh(&format!("{} ancestors of last", name), 10, rules, |rules| {
+            rules.ancestors(&color(COLORS - 1)).unwrap().len()
+        });
+    }
+}
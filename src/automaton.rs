@@ -0,0 +1,652 @@
+//! A cellular automaton engine shared by the grid simulations.
+//!
+//! An automaton is configured by three independent parts: a [`Grid`] of
+//! cells in any state, the [`Neighbours`] of each cell, and a [`Rule`]
+//! computing the next state of a cell from its current state and the states
+//! of its neighbours.
+
+use std::collections::hash_map::{DefaultHasher, Entry, HashMap};
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
+
+/// Position as (row, col)
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position(pub usize, pub usize);
+
+/// Row and column offsets of the eight surrounding cells.
+pub const MOORE: [(isize, isize); 8] = [
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, -1),
+    (0, 1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+];
+
+/// Row and column offsets of the four orthogonally adjacent cells.
+pub const VON_NEUMANN: [(isize, isize); 4] = [(-1, 0), (0, -1), (0, 1), (1, 0)];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Row `row`, counted from 1, is not as wide as the first row.
+    Ragged {
+        row: usize,
+        width: usize,
+    },
+    UnknownCell {
+        row: usize,
+        cell: char,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ragged { row, width } => write!(f, "Row {} is not {} cells wide", row, width),
+            Self::UnknownCell { row, cell } => write!(f, "Unknown cell {:?} in row {}", cell, row),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A grid of cells stored row by row, with a second buffer that each
+/// generation is written into before the two are swapped.
+#[derive(Debug, Clone)]
+pub struct Grid<S> {
+    width: usize,
+    height: usize,
+    cells: Vec<S>,
+    next: Vec<S>,
+}
+
+impl<S: PartialEq> PartialEq for Grid<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width && self.cells == other.cells
+    }
+}
+
+impl<S: Eq> Eq for Grid<S> {}
+
+impl<S: Display> Display for Grid<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.cells.chunks(self.width.max(1)) {
+            for cell in row {
+                write!(f, "{}", cell)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S> Grid<S> {
+    /// Creates a grid from its cells in row order.
+    ///
+    /// # Panics
+    ///
+    /// If the cells do not fill a whole number of rows.
+    pub fn new(width: usize, cells: Vec<S>) -> Self {
+        let height = cells.len().checked_div(width).unwrap_or(0);
+        assert_eq!(width * height, cells.len(), "incomplete row");
+        Self {
+            width,
+            height,
+            next: Vec::with_capacity(cells.len()),
+            cells,
+        }
+    }
+
+    /// Parses a grid with one row per line, converting each character with
+    /// `cell`, which returns `None` for characters that are not cells.
+    /// Leading and trailing whitespace on each line is ignored.
+    pub fn parse(s: &str, mut cell: impl FnMut(char) -> Option<S>) -> Result<Self, ParseError> {
+        let rows: Vec<&str> = s.trim().lines().map(str::trim).collect();
+        let width = rows.first().map_or(0, |r| r.chars().count());
+
+        let mut cells = Vec::with_capacity(width * rows.len());
+        for (r, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(ParseError::Ragged { row: r + 1, width });
+            }
+            for c in row.chars() {
+                cells.push(cell(c).ok_or(ParseError::UnknownCell {
+                    row: r + 1,
+                    cell: c,
+                })?);
+            }
+        }
+        Ok(Self::new(width, cells))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The cells in row order.
+    pub fn cells(&self) -> &[S] {
+        &self.cells
+    }
+
+    pub fn get(&self, p: &Position) -> Option<&S> {
+        self.index(p).map(|i| &self.cells[i])
+    }
+
+    /// The index into [`Grid::cells`] of the cell at `p`.
+    pub fn index(&self, p: &Position) -> Option<usize> {
+        if p.0 < self.height && p.1 < self.width {
+            Some(p.0 * self.width + p.1)
+        } else {
+            None
+        }
+    }
+
+    pub fn position(&self, i: usize) -> Position {
+        Position(i / self.width, i % self.width)
+    }
+
+    /// The index of the cell one step from cell `i` in direction `(dr, dc)`.
+    pub fn offset(&self, i: usize, (dr, dc): (isize, isize)) -> Option<usize> {
+        let row = (i / self.width) as isize + dr;
+        let col = (i % self.width) as isize + dc;
+        if row < 0 || col < 0 || row as usize >= self.height || col as usize >= self.width {
+            None
+        } else {
+            Some(row as usize * self.width + col as usize)
+        }
+    }
+
+    /// The index of the first cell from cell `i` in direction `d` that
+    /// `visible` accepts, looking past every other cell.
+    pub fn first_visible(
+        &self,
+        mut i: usize,
+        d: (isize, isize),
+        visible: impl Fn(&S) -> bool,
+    ) -> Option<usize> {
+        loop {
+            i = self.offset(i, d)?;
+            if visible(&self.cells[i]) {
+                return Some(i);
+            }
+        }
+    }
+
+    /// Counts the cells matching `f`.
+    pub fn count(&self, f: impl Fn(&S) -> bool) -> usize {
+        self.cells.iter().filter(|cell| f(cell)).count()
+    }
+}
+
+impl<S: PartialEq> Grid<S> {
+    /// Computes the next generation cell by cell from the current one,
+    /// returning whether any cell changed state.
+    pub fn evolve(&mut self, mut f: impl FnMut(&Self, usize) -> S) -> bool {
+        let mut next = std::mem::take(&mut self.next);
+        next.clear();
+        next.extend((0..self.cells.len()).map(|i| f(self, i)));
+        self.swap_buffers(next)
+    }
+
+    /// Makes `next` the current generation, returning whether it differs from
+    /// the previous one.
+    fn swap_buffers(&mut self, next: Vec<S>) -> bool {
+        let changed = next != self.cells;
+        self.next = std::mem::replace(&mut self.cells, next);
+        changed
+    }
+}
+
+impl<S: Copy + PartialEq> Grid<S> {
+    /// Advances one generation, applying `rule` to every cell and its
+    /// `neighbours`. Returns whether any cell changed state.
+    pub fn step<R: Rule<S>>(&mut self, neighbours: &Neighbours, rule: &R) -> bool {
+        let mut next = std::mem::take(&mut self.next);
+        next.clear();
+        let cells = &self.cells;
+        next.extend(
+            cells
+                .iter()
+                .enumerate()
+                .map(|(i, &cell)| rule.next(cell, neighbours.around(cells, i))),
+        );
+        self.swap_buffers(next)
+    }
+
+    /// Like [`Grid::step`], updating cells in parallel.
+    #[cfg(feature = "parallel")]
+    pub fn par_step<R>(&mut self, neighbours: &Neighbours, rule: &R) -> bool
+    where
+        S: Send + Sync,
+        R: Rule<S> + Sync,
+    {
+        use rayon::prelude::*;
+
+        let mut next = std::mem::take(&mut self.next);
+        next.clear();
+        let cells = &self.cells;
+        cells
+            .par_iter()
+            .enumerate()
+            .map(|(i, &cell)| rule.next(cell, neighbours.around(cells, i)))
+            .collect_into_vec(&mut next);
+        self.swap_buffers(next)
+    }
+}
+
+/// How a simulation driven by [`Grid::run`] ended. Generation 0 is the grid
+/// the run started from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Stepping `generation` leaves it unchanged.
+    Stable { generation: usize },
+    /// Generation `start` recurs every `period` generations, with `period`
+    /// at least 2.
+    Periodic { start: usize, period: usize },
+    /// No generation repeated within the generation limit.
+    GenerationLimit,
+}
+
+impl<S: Copy + PartialEq + Hash> Grid<S> {
+    /// Steps until a generation repeats, or at most `limit` times.
+    ///
+    /// Each generation is remembered by a 64-bit hash of its cells rather
+    /// than by the cells themselves, so long runs on large grids do not keep
+    /// every generation in memory. The grid is left at the last generation
+    /// computed, which for a periodic run is the first repeat of `start`.
+    pub fn run<R: Rule<S>>(&mut self, neighbours: &Neighbours, rule: &R, limit: usize) -> Outcome {
+        let mut seen = HashMap::new();
+        seen.insert(self.fingerprint(), 0);
+        for generation in 0..limit {
+            if !self.step(neighbours, rule) {
+                return Outcome::Stable { generation };
+            }
+            match seen.entry(self.fingerprint()) {
+                Entry::Occupied(start) => {
+                    let start = *start.get();
+                    return Outcome::Periodic {
+                        start,
+                        period: generation + 1 - start,
+                    };
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(generation + 1);
+                }
+            }
+        }
+        Outcome::GenerationLimit
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.cells.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// The cells considered neighbours of each cell, stored as one flat list of
+/// cell indices with the range for each cell given by `offsets`.
+///
+/// Only valid for grids of the size it was computed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbours {
+    width: usize,
+    offsets: Vec<usize>,
+    cells: Vec<usize>,
+}
+
+impl Neighbours {
+    /// Builds a custom neighbourhood, calling `f` with the index of every
+    /// cell to push the indices of its neighbours.
+    pub fn custom<S>(grid: &Grid<S>, mut f: impl FnMut(usize, &mut Vec<usize>)) -> Self {
+        let mut offsets = Vec::with_capacity(grid.cells.len() + 1);
+        let mut cells = Vec::new();
+        offsets.push(0);
+        for i in 0..grid.cells.len() {
+            f(i, &mut cells);
+            offsets.push(cells.len());
+        }
+        Self {
+            width: grid.width,
+            offsets,
+            cells,
+        }
+    }
+
+    /// The cells at the given row and column offsets from each cell that lie
+    /// inside the grid.
+    pub fn offsets<S>(grid: &Grid<S>, offsets: &[(isize, isize)]) -> Self {
+        Self::custom(grid, |i, out| {
+            out.extend(offsets.iter().filter_map(|&d| grid.offset(i, d)))
+        })
+    }
+
+    /// The eight surrounding cells.
+    pub fn moore<S>(grid: &Grid<S>) -> Self {
+        Self::offsets(grid, &MOORE)
+    }
+
+    /// The four orthogonally adjacent cells.
+    pub fn von_neumann<S>(grid: &Grid<S>) -> Self {
+        Self::offsets(grid, &VON_NEUMANN)
+    }
+
+    /// The first cell `visible` accepts in each of the eight directions.
+    /// Computed from the current generation, so only meaningful while the
+    /// cells `visible` accepts stay the same.
+    pub fn line_of_sight<S>(grid: &Grid<S>, visible: impl Fn(&S) -> bool) -> Self {
+        Self::custom(grid, |i, out| {
+            out.extend(
+                MOORE
+                    .iter()
+                    .filter_map(|&d| grid.first_visible(i, d, &visible)),
+            )
+        })
+    }
+
+    fn indices(&self, i: usize) -> &[usize] {
+        &self.cells[self.offsets[i]..self.offsets[i + 1]]
+    }
+
+    /// The neighbours of the cell at index `i` of `cells`.
+    pub fn around<'a, S>(&'a self, cells: &'a [S], i: usize) -> Neighbourhood<'a, S> {
+        Neighbourhood {
+            cells,
+            indices: self.indices(i),
+        }
+    }
+
+    pub fn of(&self, p: &Position) -> impl Iterator<Item = Position> + '_ {
+        let width = self.width;
+        let indices = match p.0.checked_mul(width).map(|i| i + p.1) {
+            Some(i) if p.1 < width && i + 1 < self.offsets.len() => self.indices(i),
+            _ => &[],
+        };
+        indices.iter().map(move |i| Position(i / width, i % width))
+    }
+}
+
+/// The states of the neighbours of one cell.
+#[derive(Debug, Clone, Copy)]
+pub struct Neighbourhood<'a, S> {
+    cells: &'a [S],
+    indices: &'a [usize],
+}
+
+impl<'a, S: Copy> Neighbourhood<'a, S> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = S> + 'a {
+        let cells = self.cells;
+        self.indices.iter().map(move |&i| cells[i])
+    }
+
+    /// Counts the neighbours matching `f`.
+    pub fn count(&self, f: impl Fn(S) -> bool) -> usize {
+        self.iter().filter(|&cell| f(cell)).count()
+    }
+}
+
+/// A transition rule, computing the next state of a cell from its current
+/// state and its neighbours.
+///
+/// Implemented for closures taking the same arguments, though closures
+/// usually need to be passed through [`rule`] for their argument types to be
+/// inferred.
+pub trait Rule<S> {
+    fn next(&self, cell: S, neighbours: Neighbourhood<'_, S>) -> S;
+}
+
+impl<S, F> Rule<S> for F
+where
+    F: Fn(S, Neighbourhood<'_, S>) -> S,
+{
+    fn next(&self, cell: S, neighbours: Neighbourhood<'_, S>) -> S {
+        self(cell, neighbours)
+    }
+}
+
+/// Identity function for building a [`Rule`] from a closure.
+pub fn rule<S, F>(f: F) -> F
+where
+    F: Fn(S, Neighbourhood<'_, S>) -> S,
+{
+    f
+}
+
+/// A rule for two-state automata where a dead cell comes alive and a live
+/// cell survives depending only on how many live neighbours it has, such as
+/// Conway's Game of Life.
+///
+/// Written in the usual `B3/S23` notation, listing the neighbour counts for
+/// birth and for survival.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LifeLike {
+    birth: [bool; 9],
+    survival: [bool; 9],
+}
+
+impl LifeLike {
+    /// Conway's Game of Life, `B3/S23`.
+    pub fn conway() -> Self {
+        "B3/S23".parse().unwrap()
+    }
+}
+
+impl std::str::FromStr for LifeLike {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let counts = |part: Option<&str>, prefix: char| {
+            let digits = part
+                .and_then(|p| p.trim().strip_prefix(prefix))
+                .ok_or(format!("Expected B<counts>/S<counts>, found {:?}", s))?;
+            let mut counts = [false; 9];
+            for c in digits.chars() {
+                match c.to_digit(10) {
+                    Some(n) if n < 9 => counts[n as usize] = true,
+                    _ => return Err(format!("Invalid neighbour count {:?} in {:?}", c, s)),
+                }
+            }
+            Ok(counts)
+        };
+        let mut parts = s.split('/');
+        let birth = counts(parts.next(), 'B')?;
+        let survival = counts(parts.next(), 'S')?;
+        if parts.next().is_some() {
+            return Err(format!("Expected B<counts>/S<counts>, found {:?}", s));
+        }
+        Ok(Self { birth, survival })
+    }
+}
+
+impl Display for LifeLike {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = |counts: &[bool; 9]| -> String {
+            (0..9)
+                .filter(|&n| counts[n])
+                .map(|n| char::from(b'0' + n as u8))
+                .collect()
+        };
+        write!(f, "B{}/S{}", digits(&self.birth), digits(&self.survival))
+    }
+}
+
+impl Rule<bool> for LifeLike {
+    fn next(&self, alive: bool, neighbours: Neighbourhood<'_, bool>) -> bool {
+        let live = neighbours.count(|cell| cell);
+        if alive {
+            self.survival.get(live) == Some(&true)
+        } else {
+            self.birth.get(live) == Some(&true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn life(s: &str) -> Grid<bool> {
+        Grid::parse(s, |c| match c {
+            '.' => Some(false),
+            '#' => Some(true),
+            _ => None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        let grid = life(".#.\n#.#");
+        assert_eq!((grid.width(), grid.height()), (3, 2));
+        assert_eq!(grid.get(&Position(1, 2)), Some(&true));
+        assert_eq!(grid.get(&Position(2, 0)), None);
+        assert_eq!(
+            Grid::parse(".#\n#", Some),
+            Err(ParseError::Ragged { row: 2, width: 2 })
+        );
+        assert_eq!(
+            Grid::<bool>::parse(".#\nx#", |c| if c == '.' { Some(false) } else { None }),
+            Err(ParseError::UnknownCell { row: 1, cell: '#' })
+        );
+    }
+
+    #[test]
+    fn neighbourhoods() {
+        let grid = life("...\n...\n...");
+        assert_eq!(Neighbours::moore(&grid).of(&Position(1, 1)).count(), 8);
+        assert_eq!(Neighbours::moore(&grid).of(&Position(0, 0)).count(), 3);
+        assert_eq!(
+            Neighbours::von_neumann(&grid)
+                .of(&Position(0, 1))
+                .collect::<Vec<_>>(),
+            vec![Position(0, 0), Position(0, 2), Position(1, 1)]
+        );
+
+        let grid = life("#..#\n....\n...#");
+        let visible = Neighbours::line_of_sight(&grid, |&cell| cell);
+        assert_eq!(
+            visible.of(&Position(0, 0)).collect::<Vec<_>>(),
+            vec![Position(0, 3)]
+        );
+        assert_eq!(visible.of(&Position(1, 2)).count(), 2);
+    }
+
+    #[test]
+    fn blinker() {
+        let mut grid = life(".....\n..#..\n..#..\n..#..\n.....");
+        let start = grid.clone();
+        let neighbours = Neighbours::moore(&grid);
+        let conway = LifeLike::conway();
+
+        assert!(grid.step(&neighbours, &conway));
+        assert_eq!(grid, life(".....\n.....\n.###.\n.....\n....."));
+        assert!(grid.step(&neighbours, &conway));
+        assert_eq!(grid, start);
+        assert_eq!(grid.count(|&cell| cell), 3);
+    }
+
+    #[test]
+    fn block_is_stable() {
+        let mut grid = life("....\n.##.\n.##.\n....");
+        let neighbours = Neighbours::moore(&grid);
+        assert!(!grid.step(&neighbours, &LifeLike::conway()));
+    }
+
+    #[test]
+    fn rule_notation() {
+        assert_eq!(LifeLike::conway().to_string(), "B3/S23");
+        assert_eq!(
+            "B36/S23".parse::<LifeLike>().unwrap().to_string(),
+            "B36/S23"
+        );
+        assert_eq!("B2/S".parse::<LifeLike>().unwrap().to_string(), "B2/S");
+        assert!("B9/S23".parse::<LifeLike>().is_err());
+        assert!("S23/B3".parse::<LifeLike>().is_err());
+        assert!("B3/S23/C2".parse::<LifeLike>().is_err());
+    }
+
+    #[test]
+    fn outcomes() {
+        let conway = LifeLike::conway();
+        let blinker = life(".....\n..#..\n..#..\n..#..\n.....");
+        let neighbours = Neighbours::moore(&blinker);
+        assert_eq!(
+            blinker.clone().run(&neighbours, &conway, 100),
+            Outcome::Periodic {
+                start: 0,
+                period: 2
+            }
+        );
+        assert_eq!(
+            blinker.clone().run(&neighbours, &conway, 1),
+            Outcome::GenerationLimit
+        );
+
+        let mut block = life("....\n.##.\n.##.\n....");
+        let neighbours = Neighbours::moore(&block);
+        assert_eq!(
+            block.run(&neighbours, &conway, 100),
+            Outcome::Stable { generation: 0 }
+        );
+
+        // A glider runs into the corner of a bounded grid and settles into
+        // a block.
+        let mut glider = life(".#....\n..#...\n###...\n......\n......\n......");
+        let neighbours = Neighbours::moore(&glider);
+        assert!(matches!(
+            glider.run(&neighbours, &conway, 100),
+            Outcome::Stable { generation } if generation > 0
+        ));
+        assert_eq!(glider.count(|&cell| cell), 4);
+    }
+
+    #[test]
+    fn closure_rules() {
+        // Each cell takes the number of its von Neumann neighbours, so the
+        // second step sees the counts of the first.
+        let mut grid = Grid::new(3, vec![0usize; 6]);
+        let neighbours = Neighbours::von_neumann(&grid);
+        let count = rule(|_, n: Neighbourhood<'_, usize>| n.len());
+        assert!(grid.step(&neighbours, &count));
+        assert_eq!(grid.cells(), &[2, 3, 2, 2, 3, 2]);
+        assert!(!grid.step(&neighbours, &count));
+
+        let sum = rule(|cell: usize, n: Neighbourhood<'_, usize>| cell + n.iter().sum::<usize>());
+        assert!(grid.step(&neighbours, &sum));
+        assert_eq!(grid.cells(), &[7, 10, 7, 7, 10, 7]);
+    }
+
+    #[test]
+    fn evolve() {
+        let mut grid = life("#..");
+        assert!(grid.evolve(|g, i| i > 0 && g.cells()[i - 1]));
+        assert_eq!(grid, life(".#."));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_step() {
+        let mut sequential = life("......\n.##...\n.#....\n....#.\n...##.\n......");
+        let mut parallel = sequential.clone();
+        let neighbours = Neighbours::moore(&sequential);
+        for _ in 0..4 {
+            let changed = sequential.step(&neighbours, &LifeLike::conway());
+            assert_eq!(parallel.par_step(&neighbours, &LifeLike::conway()), changed);
+            assert_eq!(sequential, parallel);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use advent_of_code_2020::day8::Program;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage:
+  day8-bytecode encode <source> <image>  assemble a text program into a binary image
+  day8-bytecode decode <image> <source>  disassemble a binary image into text
+  day8-bytecode check <image>            verify a binary image";
+
+fn fail(path: &str, error: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, error);
+    process::exit(1);
+}
+
+fn load_image(path: &str) -> Program {
+    let bytes = fs::read(path).unwrap_or_else(|e| fail(path, e));
+    Program::from_bytes(&bytes).unwrap_or_else(|e| fail(path, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["encode", source, image] => {
+            let text = fs::read_to_string(source).unwrap_or_else(|e| fail(source, e));
+            let program = Program::assemble(&text).unwrap_or_else(|e| {
+                eprintln!("{}:{}", source, e);
+                process::exit(1);
+            });
+            fs::write(image, program.to_bytes()).unwrap_or_else(|e| fail(image, e));
+        }
+        ["decode", image, source] => {
+            let program = load_image(image);
+            fs::write(source, program.disassemble()).unwrap_or_else(|e| fail(source, e));
+        }
+        ["check", image] => {
+            let program = load_image(image);
+            println!("{}: {} instructions", image, program.len());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
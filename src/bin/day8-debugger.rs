@@ -0,0 +1,156 @@
+use advent_of_code_2020::day8::{Breakpoint, Debugger, Program, Stop, Watchpoint};
+use std::io::{self, BufRead, Write};
+use std::{env, fs, process};
+
+const HELP: &str = "Commands:
+  s, step              execute one instruction
+  b, back              undo the last instruction
+  c, continue          run until a breakpoint, a watchpoint or termination
+  break <line|opcode>  stop before a line, or any instruction with an opcode
+  delete <line|opcode> remove a breakpoint
+  watch [value]        stop when the accumulator changes [to value]
+  unwatch [value]      remove a watchpoint
+  p, print             show the current state
+  l, list              show the instructions around the current one
+  v, visited           show the lines executed so far
+  history              show the line and accumulator before each step
+  q, quit              exit";
+
+fn watchpoint(arg: Option<&str>) -> Result<Watchpoint, String> {
+    match arg {
+        None => Ok(Watchpoint::Changed),
+        Some(value) => value
+            .parse()
+            .map(Watchpoint::Equals)
+            .map_err(|_| format!("Invalid value {:?}", value)),
+    }
+}
+
+fn print_state(debugger: &Debugger) {
+    match debugger.current() {
+        Some(inst) => println!(
+            "pc {:>5}  acc {:>6}  {}",
+            debugger.pc(),
+            debugger.accumulator(),
+            inst
+        ),
+        None => println!(
+            "pc {:>5}  acc {:>6}  <end of program>",
+            debugger.pc(),
+            debugger.accumulator()
+        ),
+    }
+}
+
+fn print_stop(debugger: &Debugger, stop: Stop) {
+    match stop {
+        Stop::Stepped => {}
+        Stop::Breakpoint { line } => println!("Breakpoint at line {}", line),
+        Stop::Watchpoint { old, new } => println!("Accumulator changed: {} -> {}", old, new),
+        Stop::Terminated(termination) => println!("Terminated: {:?}", termination),
+    }
+    print_state(debugger);
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: day8-debugger <program>");
+            process::exit(2);
+        }
+    };
+    let program: Program = match fs::read_to_string(&path).map(|source| source.parse()) {
+        Ok(Ok(program)) => program,
+        Ok(Err(e)) => {
+            eprintln!("{}:{}", path, e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut debugger = Debugger::new(&program);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    print_state(&debugger);
+    loop {
+        print!("(day8) ");
+        io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let arg = words.next();
+
+        match (command, arg) {
+            ("s", _) | ("step", _) => {
+                let stop = debugger.step();
+                print_stop(&debugger, stop);
+            }
+            ("b", _) | ("back", _) => {
+                if !debugger.step_back() {
+                    println!("Already at the start");
+                }
+                print_state(&debugger);
+            }
+            ("c", _) | ("continue", _) => {
+                let stop = debugger.continue_execution();
+                print_stop(&debugger, stop);
+            }
+            ("break", Some(arg)) => match arg.parse::<Breakpoint>() {
+                Ok(breakpoint) => debugger.add_breakpoint(breakpoint),
+                Err(e) => println!("{}", e),
+            },
+            ("delete", Some(arg)) => match arg.parse::<Breakpoint>() {
+                Ok(breakpoint) => {
+                    if !debugger.remove_breakpoint(&breakpoint) {
+                        println!("No such breakpoint");
+                    }
+                }
+                Err(e) => println!("{}", e),
+            },
+            ("watch", arg) => match watchpoint(arg) {
+                Ok(watchpoint) => debugger.add_watchpoint(watchpoint),
+                Err(e) => println!("{}", e),
+            },
+            ("unwatch", arg) => match watchpoint(arg) {
+                Ok(watchpoint) => {
+                    if !debugger.remove_watchpoint(&watchpoint) {
+                        println!("No such watchpoint");
+                    }
+                }
+                Err(e) => println!("{}", e),
+            },
+            ("p", _) | ("print", _) => print_state(&debugger),
+            ("l", _) | ("list", _) => {
+                let pc = debugger.pc();
+                for line in pc.saturating_sub(5)..(pc + 6).min(program.len()) {
+                    let marker = if line == pc { "=>" } else { "  " };
+                    println!("{} {:>5}  {}", marker, line, program.get(line).unwrap());
+                }
+            }
+            ("v", _) | ("visited", _) => {
+                let mut visited: Vec<_> = debugger.visited().iter().collect();
+                visited.sort();
+                println!("{:?}", visited);
+            }
+            ("history", _) => {
+                for (line, acc) in debugger.history() {
+                    println!("{:>5}  acc {:>6}", line, acc);
+                }
+            }
+            ("q", _) | ("quit", _) => break,
+            _ => println!("{}", HELP),
+        }
+    }
+}
@@ -1,34 +1,409 @@
 use aoc_runner_derive::{aoc, aoc_generator};
-use std::{collections::HashMap, num::ParseIntError};
+use std::fmt::{self, Display};
+use std::ops::RangeInclusive;
+use std::{collections::BTreeMap, num::ParseIntError};
 
-#[aoc_generator(day10)]
-fn parse_input_day10(input: &str) -> Result<Vec<u8>, ParseIntError> {
-    input.trim().lines().map(|l| l.trim().parse()).collect()
+use crate::params;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Param(params::Error),
+    /// The number of arrangements does not fit in the counter type.
+    Overflow,
+    /// The step from `from` jolts to `to` jolts is outside the tolerance.
+    Gap {
+        from: u64,
+        to: u64,
+    },
+    /// No chain of adapters reaches the device.
+    NoChain {
+        device: u64,
+    },
+    /// Two adapters share a rating, so they cannot both be in the chain.
+    /// Also reported for an adapter rated like the outlet or the device.
+    Duplicate(u64),
+    /// The device rating does not fit in a `u64`.
+    DeviceOverflow,
 }
 
-#[aoc(day10, part1)]
-pub fn part1(adaptors: &[u8]) -> usize {
-    let mut sorted_adaptors = adaptors.to_owned();
-    sorted_adaptors.sort();
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Param(e) => write!(f, "{}", e),
+            Self::Overflow => write!(f, "Too many arrangements to count"),
+            Self::Gap { from, to } => {
+                write!(f, "No adapter between {} and {} jolts", from, to)
+            }
+            Self::Duplicate(joltage) => write!(f, "More than one {} jolt adapter", joltage),
+            Self::NoChain { device } => write!(f, "No chain reaches the {} jolt device", device),
+            Self::DeviceOverflow => write!(f, "Device rating is too high"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<params::Error> for Error {
+    fn from(e: params::Error) -> Self {
+        Self::Param(e)
+    }
+}
+
+/// Number types that arrangements can be counted in.
+pub trait Count: Clone {
+    fn zero() -> Self;
+    fn one() -> Self;
+    /// `None` if the sum does not fit.
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+}
+
+impl Count for u64 {
+    fn zero() -> Self {
+        0
+    }
+
+    fn one() -> Self {
+        1
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        u64::checked_add(*self, *other)
+    }
+}
+
+/// An unsigned integer of any size, supporting just what counting needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigCount {
+    /// Base 2^32 digits, least significant first, without trailing zeros.
+    digits: Vec<u32>,
+}
+
+impl Count for BigCount {
+    fn zero() -> Self {
+        Self { digits: Vec::new() }
+    }
+
+    fn one() -> Self {
+        Self { digits: vec![1] }
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        let len = self.digits.len().max(other.digits.len());
+        let mut digits = Vec::with_capacity(len + 1);
+        let mut carry = 0;
+        for i in 0..len {
+            let sum = *self.digits.get(i).unwrap_or(&0) as u64
+                + *other.digits.get(i).unwrap_or(&0) as u64
+                + carry;
+            digits.push(sum as u32);
+            carry = sum >> 32;
+        }
+        if carry > 0 {
+            digits.push(carry as u32);
+        }
+        Some(Self { digits })
+    }
+}
+
+impl From<u64> for BigCount {
+    fn from(n: u64) -> Self {
+        let mut digits = vec![n as u32, (n >> 32) as u32];
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        Self { digits }
+    }
+}
+
+impl Display for BigCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const BASE: u64 = 1_000_000_000;
+
+        // Repeatedly divide by 10^9, collecting the remainders as decimal
+        // chunks, least significant first.
+        let mut digits = self.digits.clone();
+        let mut chunks = Vec::new();
+        while !digits.is_empty() {
+            let mut remainder = 0;
+            for digit in digits.iter_mut().rev() {
+                let value = (remainder << 32) | *digit as u64;
+                *digit = (value / BASE) as u32;
+                remainder = value % BASE;
+            }
+            chunks.push(remainder);
+            while digits.last() == Some(&0) {
+                digits.pop();
+            }
+        }
+
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, rest)) => {
+                write!(f, "{}", first)?;
+                for chunk in rest.iter().rev() {
+                    write!(f, "{:09}", chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How adapters, the outlet and the device can be connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoltageRules {
+    /// Joltage differences an adapter accepts from what it is plugged into.
+    pub tolerance: RangeInclusive<u32>,
+    /// Rating of the outlet the chain starts from.
+    pub outlet: u64,
+    /// How far above the highest adapter the device is rated.
+    pub device_offset: u64,
+}
+
+impl Default for JoltageRules {
+    fn default() -> Self {
+        Self {
+            tolerance: 1..=3,
+            outlet: 0,
+            device_offset: 3,
+        }
+    }
+}
+
+/// Best chain found so far ending at a joltage: its length in adapters and
+/// the index of the previous joltage.
+type Link = Option<(usize, usize)>;
+
+impl JoltageRules {
+    /// Rules from `--param min_step=<jolts>`, `tolerance=<jolts>` (the
+    /// largest step), `outlet=<jolts>` and `device_offset=<jolts>`. The
+    /// smallest step may not be larger than the largest.
+    pub fn from_params() -> Result<Self, params::Error> {
+        let default = Self::default();
+        let min_step = params::get_or("min_step", *default.tolerance.start())?;
+        let max_step = params::get_or("tolerance", *default.tolerance.end())?;
+        if min_step > max_step {
+            return Err(params::Error::Invalid {
+                key: "min_step".to_owned(),
+                value: min_step.to_string(),
+            });
+        }
+        Ok(Self {
+            tolerance: min_step..=max_step,
+            outlet: params::get_or("outlet", default.outlet)?,
+            device_offset: params::get_or("device_offset", default.device_offset)?,
+        })
+    }
+
+    fn accepts(&self, step: u64) -> bool {
+        step >= *self.tolerance.start() as u64 && step <= *self.tolerance.end() as u64
+    }
+
+    pub fn device(&self, adapters: &[u32]) -> Result<u64, Error> {
+        adapters
+            .iter()
+            .max()
+            .map_or(self.outlet, |&max| max as u64)
+            .checked_add(self.device_offset)
+            .ok_or(Error::DeviceOverflow)
+    }
+
+    /// The outlet, every adapter rated at least as high in increasing order,
+    /// then the device.
+    fn joltages(&self, adapters: &[u32]) -> Result<Vec<u64>, Error> {
+        let mut joltages = vec![self.outlet];
+        joltages.extend(
+            adapters
+                .iter()
+                .map(|&a| a as u64)
+                .filter(|&a| a >= self.outlet),
+        );
+        joltages[1..].sort_unstable();
+        joltages.push(self.device(adapters)?);
+        Ok(joltages)
+    }
+
+    /// Fails if two of the sorted `joltages` are equal and the tolerance
+    /// does not allow a step of 0, as such adapters cannot both be chained.
+    fn check_duplicates(&self, joltages: &[u64]) -> Result<(), Error> {
+        if self.accepts(0) {
+            return Ok(());
+        }
+        match joltages.windows(2).find(|pair| pair[0] == pair[1]) {
+            Some(pair) => Err(Error::Duplicate(pair[1])),
+            None => Ok(()),
+        }
+    }
+
+    /// Indices of the joltages before `i` that joltage `i` can be plugged
+    /// into, nearest first.
+    fn predecessors<'a>(
+        &'a self,
+        joltages: &'a [u64],
+        i: usize,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let joltage = joltages[i];
+        (0..i)
+            .rev()
+            .take_while(move |&j| joltage - joltages[j] <= *self.tolerance.end() as u64)
+            .filter(move |&j| self.accepts(joltage - joltages[j]))
+    }
+
+    /// Chains every adapter, failing at the first step outside the tolerance.
+    pub fn analyze(&self, adapters: &[u32]) -> Result<ChainAnalysis, Error> {
+        let mut sorted = adapters.to_owned();
+        sorted.sort_unstable();
+
+        let device = self.device(adapters)?;
+        let mut differences = BTreeMap::new();
+        let mut reached = self.outlet;
+        for adapter in sorted.iter().map(|&a| a as u64).chain(Some(device)) {
+            match adapter.checked_sub(reached) {
+                Some(step) if self.accepts(step) => {
+                    *differences.entry(step as u32).or_insert(0) += 1
+                }
+                Some(0) => return Err(Error::Duplicate(adapter)),
+                _ => {
+                    return Err(Error::Gap {
+                        from: reached,
+                        to: adapter,
+                    })
+                }
+            }
+            reached = adapter;
+        }
+
+        Ok(ChainAnalysis {
+            differences,
+            device,
+        })
+    }
+
+    /// Counts the distinct chains from the outlet to the device.
+    ///
+    /// Runs in `O(n log n + n * k)` time, `k` being the number of adapters
+    /// within the tolerance of each other. Adapters sharing a rating are
+    /// rejected as in [`JoltageRules::analyze`], and a count overflowing `C`
+    /// is reported as [`Error::Overflow`].
+    pub fn count_arrangements<C: Count>(&self, adapters: &[u32]) -> Result<C, Error> {
+        let joltages = self.joltages(adapters)?;
+        self.check_duplicates(&joltages)?;
+        let mut ways: Vec<C> = Vec::with_capacity(joltages.len());
+        ways.push(C::one());
+        for i in 1..joltages.len() {
+            let mut count = C::zero();
+            for j in self.predecessors(&joltages, i) {
+                count = count.checked_add(&ways[j]).ok_or(Error::Overflow)?;
+            }
+            ways.push(count);
+        }
 
-    println!("Found all my adaptors: {:?}!", sorted_adaptors);
+        Ok(ways.pop().unwrap())
+    }
 
-    let mut reached_jolts: u8 = 0;
-    let mut diff_cnt: HashMap<u8, usize> = HashMap::new();
-    for adaptor in sorted_adaptors {
-        print!("Testing adaptor {:?}... ", adaptor);
-        if adaptor - reached_jolts <= 3 {
-            println!("Success {:?}", adaptor - reached_jolts);
+    /// Finds, for every joltage, the chain from the outlet with the most
+    /// adapters if `longest`, or the fewest otherwise. Ties go to the chain
+    /// through the highest rated adapter.
+    fn best_chain(&self, adapters: &[u32], longest: bool) -> Result<Vec<u32>, Error> {
+        let joltages = self.joltages(adapters)?;
+        let mut best: Vec<Link> = vec![None; joltages.len()];
+        best[0] = Some((0, 0));
+        for i in 1..joltages.len() {
+            for j in self.predecessors(&joltages, i) {
+                let len = match best[j] {
+                    Some((len, _)) => len + 1,
+                    None => continue,
+                };
+                let better = match best[i] {
+                    None => true,
+                    Some((current, _)) if longest => len > current,
+                    Some((current, _)) => len < current,
+                };
+                if better {
+                    best[i] = Some((len, j));
+                }
+            }
+        }
 
-            *diff_cnt.entry(adaptor - reached_jolts).or_insert(0) += 1;
-            reached_jolts = adaptor;
+        let device = joltages.len() - 1;
+        if best[device].is_none() {
+            return Err(Error::NoChain {
+                device: joltages[device],
+            });
         }
+
+        let mut chain = Vec::new();
+        let mut i = device;
+        while let Some((_, prev)) = best[i].filter(|_| i > 0) {
+            if i != device {
+                chain.push(joltages[i] as u32);
+            }
+            i = prev;
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// The chain reaching the device through as many adapters as possible.
+    pub fn longest_chain(&self, adapters: &[u32]) -> Result<Vec<u32>, Error> {
+        self.best_chain(adapters, true)
+    }
+
+    /// The chain reaching the device through as few adapters as possible.
+    pub fn shortest_chain(&self, adapters: &[u32]) -> Result<Vec<u32>, Error> {
+        self.best_chain(adapters, false)
+    }
+
+    /// The fewest adapters to remove so that the rest chain from the outlet
+    /// to the device, which are those left out of the longest chain.
+    pub fn removable(&self, adapters: &[u32]) -> Result<Vec<u32>, Error> {
+        let mut chain = self.longest_chain(adapters)?.into_iter().peekable();
+        let mut sorted = adapters.to_owned();
+        sorted.sort_unstable();
+        Ok(sorted
+            .into_iter()
+            .filter(|&a| chain.next_if_eq(&a).is_none())
+            .collect())
     }
+}
+
+/// Joltage steps of the chain using every adapter, from the outlet to the
+/// device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainAnalysis {
+    /// Number of steps of each size, including the step to the device.
+    pub differences: BTreeMap<u32, usize>,
+    /// Rating of the device.
+    pub device: u64,
+}
+
+impl ChainAnalysis {
+    /// Chains every adapter with the default rules, failing at the first
+    /// step that is not 1 to 3 jolts.
+    pub fn new(adapters: &[u32]) -> Result<Self, Error> {
+        JoltageRules::default().analyze(adapters)
+    }
+
+    /// Number of steps of `difference` jolts.
+    pub fn count(&self, difference: u32) -> usize {
+        self.differences.get(&difference).copied().unwrap_or(0)
+    }
+}
 
-    // Last difference of 3 that my device auto-tollerates
-    *diff_cnt.entry(3).or_insert(0) += 1;
+#[aoc_generator(day10)]
+fn parse_input_day10(input: &str) -> Result<Vec<u32>, ParseIntError> {
+    input.trim().lines().map(|l| l.trim().parse()).collect()
+}
 
-    diff_cnt.get(&1).unwrap_or(&0) * diff_cnt.get(&3).unwrap_or(&0)
+#[aoc(day10, part1)]
+pub fn part1(adaptors: &[u32]) -> Result<usize, Error> {
+    let analysis = JoltageRules::from_params()?.analyze(adaptors)?;
+    Ok(analysis.count(1) * analysis.count(3))
+}
+
+#[aoc(day10, part2)]
+pub fn part2(adaptors: &[u32]) -> Result<u64, Error> {
+    JoltageRules::from_params()?.count_arrangements(adaptors)
 }
 
 #[cfg(test)]
@@ -82,12 +457,252 @@ mod tests {
     #[test]
     fn sample1() {
         let parsed = parse_input_day10(SAMPLE).unwrap();
-        assert_eq!(part1(&parsed), 7 * 5);
+        assert_eq!(part1(&parsed), Ok(7 * 5));
     }
 
     #[test]
     fn sample2() {
         let parsed = parse_input_day10(SAMPLE2).unwrap();
-        assert_eq!(part1(&parsed), 22 * 10);
+        assert_eq!(part1(&parsed), Ok(22 * 10));
+    }
+
+    #[test]
+    fn chain_analysis() {
+        let parsed = parse_input_day10(SAMPLE).unwrap();
+        let analysis = ChainAnalysis::new(&parsed).unwrap();
+        assert_eq!(analysis.device, 22);
+        assert_eq!(
+            analysis.differences.into_iter().collect::<Vec<_>>(),
+            vec![(1, 7), (3, 5)]
+        );
+
+        let adapters: Vec<u32> = (1..=1000).map(|i| i * 3).collect();
+        let analysis = ChainAnalysis::new(&adapters).unwrap();
+        assert_eq!(analysis.device, 3003);
+        assert_eq!(analysis.count(3), 1001);
+        assert_eq!(analysis.count(1), 0);
+
+        let analysis = ChainAnalysis::new(&[]).unwrap();
+        assert_eq!(analysis.device, 3);
+        assert_eq!(analysis.count(3), 1);
+    }
+
+    #[test]
+    fn broken_chains() {
+        assert_eq!(
+            ChainAnalysis::new(&[1, 2, 6, 7]),
+            Err(Error::Gap { from: 2, to: 6 })
+        );
+        assert_eq!(ChainAnalysis::new(&[4]), Err(Error::Gap { from: 0, to: 4 }));
+        assert_eq!(ChainAnalysis::new(&[2, 1, 2]), Err(Error::Duplicate(2)));
+        assert_eq!(part1(&[1, 5]), Err(Error::Gap { from: 1, to: 5 }));
+
+        let analysis = ChainAnalysis::new(&[u32::MAX - 1, u32::MAX]);
+        assert_eq!(
+            analysis,
+            Err(Error::Gap {
+                from: 0,
+                to: u32::MAX as u64 - 1
+            })
+        );
+    }
+
+    #[test]
+    fn optimal_chains() {
+        let parsed = parse_input_day10(SAMPLE).unwrap();
+        let rules = JoltageRules::default();
+        let mut sorted = parsed.clone();
+        sorted.sort_unstable();
+        assert_eq!(rules.longest_chain(&parsed), Ok(sorted));
+        assert_eq!(
+            rules.shortest_chain(&parsed),
+            Ok(vec![1, 4, 7, 10, 12, 15, 16, 19])
+        );
+        assert_eq!(rules.removable(&parsed), Ok(vec![]));
+    }
+
+    #[test]
+    fn custom_rules() {
+        let adapters = [7, 5, 4, 3, 2, 1];
+        let rules = JoltageRules {
+            tolerance: 2..=3,
+            ..JoltageRules::default()
+        };
+        assert_eq!(rules.analyze(&adapters), Err(Error::Gap { from: 0, to: 1 }));
+        assert_eq!(rules.longest_chain(&adapters), Ok(vec![3, 5, 7]));
+        assert_eq!(rules.shortest_chain(&adapters), Ok(vec![3, 5, 7]));
+        assert_eq!(rules.removable(&adapters), Ok(vec![1, 2, 4]));
+        assert_eq!(rules.count_arrangements::<u64>(&adapters), Ok(3));
+
+        let rules = JoltageRules {
+            outlet: 10,
+            device_offset: 1,
+            ..JoltageRules::default()
+        };
+        let adapters = [5, 11, 13, 13];
+        assert_eq!(rules.device(&adapters), Ok(14));
+        assert_eq!(
+            rules.analyze(&adapters),
+            Err(Error::Gap { from: 10, to: 5 })
+        );
+        assert_eq!(rules.longest_chain(&adapters), Ok(vec![11, 13]));
+        assert_eq!(rules.removable(&adapters), Ok(vec![5, 13]));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&adapters),
+            Err(Error::Duplicate(13))
+        );
+        assert_eq!(rules.count_arrangements::<u64>(&adapters[..3]), Ok(3));
+
+        assert_eq!(
+            JoltageRules::default().longest_chain(&[1, 5]),
+            Err(Error::NoChain { device: 8 })
+        );
+    }
+
+    #[test]
+    fn rules_from_params() {
+        assert_eq!(JoltageRules::from_params(), Ok(JoltageRules::default()));
+        params::set("min_step", "2");
+        params::set("outlet", "10");
+        assert_eq!(
+            JoltageRules::from_params(),
+            Ok(JoltageRules {
+                tolerance: 2..=3,
+                outlet: 10,
+                device_offset: 3
+            })
+        );
+
+        params::set("min_step", "4");
+        assert_eq!(
+            part1(&[1, 2, 3]),
+            Err(Error::Param(params::Error::Invalid {
+                key: "min_step".to_owned(),
+                value: "4".to_owned()
+            }))
+        );
+        params::clear();
+    }
+
+    #[test]
+    fn arrangements() {
+        let parsed = parse_input_day10(SAMPLE).unwrap();
+        assert_eq!(part2(&parsed), Ok(8));
+        let parsed = parse_input_day10(SAMPLE2).unwrap();
+        assert_eq!(part2(&parsed), Ok(19208));
+        assert_eq!(
+            JoltageRules::default().count_arrangements::<BigCount>(&parsed),
+            Ok(BigCount::from(19208))
+        );
+
+        params::set("tolerance", "1");
+        assert_eq!(part2(&parsed), Ok(0));
+        params::set("tolerance", "4");
+        assert!(part2(&parsed).unwrap() > 19208);
+        params::clear();
+    }
+
+    #[test]
+    fn device_overflow() {
+        let rules = JoltageRules {
+            device_offset: u64::MAX,
+            ..JoltageRules::default()
+        };
+        assert_eq!(rules.device(&[]), Ok(u64::MAX));
+        assert_eq!(rules.device(&[1]), Err(Error::DeviceOverflow));
+        assert_eq!(rules.analyze(&[1]), Err(Error::DeviceOverflow));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&[1]),
+            Err(Error::DeviceOverflow)
+        );
+        assert_eq!(rules.longest_chain(&[1]), Err(Error::DeviceOverflow));
+
+        params::set("device_offset", &u64::MAX.to_string());
+        assert_eq!(part2(&[1, 2]), Err(Error::DeviceOverflow));
+        params::clear();
+    }
+
+    #[test]
+    fn duplicates() {
+        let rules = JoltageRules::default();
+        let adapters = [1, 2, 2, 3];
+        assert_eq!(rules.analyze(&adapters), Err(Error::Duplicate(2)));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&adapters),
+            Err(Error::Duplicate(2))
+        );
+
+        // An adapter rated like the outlet or the device is a duplicate too.
+        let rules = JoltageRules {
+            outlet: 1,
+            device_offset: 0,
+            ..JoltageRules::default()
+        };
+        assert_eq!(rules.analyze(&[1, 2]), Err(Error::Duplicate(1)));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&[1, 2]),
+            Err(Error::Duplicate(1))
+        );
+        assert_eq!(rules.analyze(&[2]), Err(Error::Duplicate(2)));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&[2]),
+            Err(Error::Duplicate(2))
+        );
+
+        // Ratings past `u32` are reported in full.
+        let rules = JoltageRules {
+            outlet: 1 << 32,
+            device_offset: 0,
+            ..JoltageRules::default()
+        };
+        assert_eq!(rules.analyze(&[]), Err(Error::Duplicate(1 << 32)));
+        assert_eq!(
+            rules.count_arrangements::<u64>(&[]),
+            Err(Error::Duplicate(1 << 32))
+        );
+
+        // Once a step of 0 is allowed, duplicates chain like any adapter.
+        let rules = JoltageRules {
+            tolerance: 0..=3,
+            ..JoltageRules::default()
+        };
+        assert!(rules.analyze(&adapters).is_ok());
+        assert!(rules.count_arrangements::<u64>(&adapters).is_ok());
+    }
+
+    #[test]
+    fn arrangements_overflow() {
+        // Every adapter one jolt apart, so the counts are the tribonacci
+        // numbers.
+        let adapters: Vec<u32> = (1..=100).collect();
+        let mut expected: [u128; 3] = [0, 0, 1];
+        for _ in 1..=100 {
+            expected = [expected[1], expected[2], expected.iter().sum()];
+        }
+
+        assert_eq!(
+            JoltageRules::default().count_arrangements::<u64>(&adapters),
+            Err(Error::Overflow)
+        );
+        assert_eq!(part2(&adapters), Err(Error::Overflow));
+        assert_eq!(
+            JoltageRules::default()
+                .count_arrangements::<BigCount>(&adapters)
+                .map(|c| c.to_string()),
+            Ok(expected[2].to_string())
+        );
+    }
+
+    #[test]
+    fn big_count_display() {
+        assert_eq!(BigCount::zero().to_string(), "0");
+        assert_eq!(BigCount::from(u64::MAX).to_string(), u64::MAX.to_string());
+        let sum = BigCount::from(u64::MAX)
+            .checked_add(&BigCount::from(1_000_000_000))
+            .unwrap();
+        assert_eq!(
+            sum.to_string(),
+            (u64::MAX as u128 + 1_000_000_000).to_string()
+        );
     }
 }
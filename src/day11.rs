@@ -1,7 +1,57 @@
+use crate::automaton::{Grid, Neighbourhood, Outcome, Rule, MOORE};
+use crate::params;
 use aoc_runner_derive::{aoc, aoc_generator};
-use std::{collections::HashMap, fmt::Display, str::FromStr};
+use std::fmt::{self, Display};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub use crate::automaton::{Neighbours, Position};
+
+/// Generations simulated before giving up, unless overridden with the
+/// `generations` parameter.
+pub const GENERATION_LIMIT: usize = 10_000;
+
+/// Parameter overriding how many occupied neighbours make a seated person
+/// leave, which is 4 in part 1 and 5 in part 2.
+pub const SEAT_TOLERANCE: &str = "seat_tolerance";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Param(params::Error),
+    /// The seating never settles: generation `start` recurs every `period`
+    /// generations.
+    Periodic {
+        start: usize,
+        period: usize,
+    },
+    /// No generation repeated within the given number of generations.
+    GenerationLimit(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Param(e) => write!(f, "{}", e),
+            Self::Periodic { start, period } => write!(
+                f,
+                "Seating never settles, generation {} recurs every {} generations",
+                start, period
+            ),
+            Self::GenerationLimit(limit) => {
+                write!(f, "Seating did not settle within {} generations", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<params::Error> for Error {
+    fn from(e: params::Error) -> Self {
+        Self::Param(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Occupancy {
     Floor,
     Empty,
@@ -41,74 +91,77 @@ impl Occupancy {
     }
 }
 
-/// Position as (row, col)
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Position(usize, usize);
-
-#[derive(Clone, PartialEq, Eq)]
-pub struct SeatLayout {
-    layout: HashMap<Position, Occupancy>,
+/// The seating rules: an empty seat with no occupied neighbours is taken,
+/// and an occupied seat with at least `tolerance` occupied neighbours is
+/// left. Floor never changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Seating {
+    pub tolerance: usize,
 }
 
-impl Display for SeatLayout {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let bp = self.layout.keys().max().unwrap();
-
-        for r in 0..=bp.0 {
-            for c in 0..=bp.1 {
-                write!(f, "{}", self.layout.get(&Position(r, c)).unwrap())?;
-            }
-            writeln!(f, "")?;
+impl Rule<Occupancy> for Seating {
+    fn next(&self, cell: Occupancy, neighbours: Neighbourhood<'_, Occupancy>) -> Occupancy {
+        match cell {
+            Occupancy::Floor => cell,
+            _ => transition(
+                cell,
+                neighbours.count(|n| Occupancy::is_occupied(&n)),
+                self.tolerance,
+            ),
         }
-        Ok(())
     }
 }
 
+/// The state of a seat in the next generation, given how many of its
+/// neighbours are occupied.
+fn transition(cell: Occupancy, occupied: usize, tolerance: usize) -> Occupancy {
+    match cell {
+        Occupancy::Empty if occupied == 0 => Occupancy::Occupied,
+        Occupancy::Occupied if occupied >= tolerance => Occupancy::Empty,
+        cell => cell,
+    }
+}
+
+/// A seat layout is an automaton grid of [`Occupancy`] cells, simulated with
+/// the [`Seating`] rule.
+pub type SeatLayout = Grid<Occupancy>;
+
+fn is_seat(cell: &Occupancy) -> bool {
+    *cell != Occupancy::Floor
+}
+
 impl SeatLayout {
     pub fn adjacent_occupied(sl: &Self, seat: &Position) -> usize {
-        let row = seat.0;
-        let col = seat.1;
-
-        let positions = [
-            Position(row.wrapping_sub(1), col.wrapping_sub(1)), // Left Up
-            Position(row.wrapping_sub(1), col),                 // Up
-            Position(row.wrapping_sub(1), col + 1),             // Right Up
-            Position(row, col.wrapping_sub(1)),                 // Left
-            Position(row, col + 1),                             // Right
-            Position(row + 1, col.wrapping_sub(1)),             // Left Down
-            Position(row + 1, col),                             // Down
-            Position(row + 1, col + 1),                         // Right Down
-        ];
-
-        positions
-            .iter()
-            .filter_map(|p| sl.layout.get(p))
-            .cloned()
-            .filter(Occupancy::is_occupied)
-            .count()
+        sl.index(seat).map_or(0, |i| {
+            MOORE
+                .iter()
+                .filter_map(|&d| sl.offset(i, d))
+                .filter(|&n| Occupancy::is_occupied(&sl.cells()[n]))
+                .count()
+        })
     }
 
+    /// Counts the occupied seats visible from `seat`, looking past floor
+    /// in each of the eight directions.
     pub fn first_occupied(sl: &Self, seat: &Position) -> usize {
-        let row = seat.0;
-        let col = seat.1;
-
-        let positions = [
-            Position(row.wrapping_sub(1), col.wrapping_sub(1)), // Left Up
-            Position(row.wrapping_sub(1), col),                 // Up
-            Position(row.wrapping_sub(1), col + 1),             // Right Up
-            Position(row, col.wrapping_sub(1)),                 // Left
-            Position(row, col + 1),                             // Right
-            Position(row + 1, col.wrapping_sub(1)),             // Left Down
-            Position(row + 1, col),                             // Down
-            Position(row + 1, col + 1),                         // Right Down
-        ];
-
-        positions
-            .iter()
-            .filter_map(|p| sl.layout.get(p))
-            .cloned()
-            .filter(Occupancy::is_occupied)
-            .count()
+        sl.index(seat).map_or(0, |i| {
+            MOORE
+                .iter()
+                .filter_map(|&d| sl.first_visible(i, d, is_seat))
+                .filter(|&n| Occupancy::is_occupied(&sl.cells()[n]))
+                .count()
+        })
+    }
+
+    /// Precomputes the eight cells surrounding every cell.
+    pub fn adjacency(&self) -> Neighbours {
+        Neighbours::moore(self)
+    }
+
+    /// Precomputes the seats visible from every cell. Seats never move, so
+    /// the result holds for every generation of the layout.
+    pub fn line_of_sight(&self) -> Neighbours {
+        Neighbours::line_of_sight(self, is_seat)
     }
 
     /// Updates the current seating model, returning whether any seats have
@@ -117,34 +170,14 @@ impl SeatLayout {
     where
         F: Fn(&Self, &Position) -> usize,
     {
-        let state = &self.clone();
-        let mut changed = false;
-
-        self.layout.iter_mut().for_each(|(s, o)| match o {
-            Occupancy::Empty => {
-                if f(state, s) == 0 {
-                    o.swap();
-                    changed |= true;
-                }
-            }
-            Occupancy::Occupied => {
-                if f(state, s) >= tolerance {
-                    o.swap();
-                    changed |= true;
-                }
-            }
-            _ => {}
-        });
-
-        changed
+        self.evolve(|sl, i| match sl.cells()[i] {
+            Occupancy::Floor => Occupancy::Floor,
+            cell => transition(cell, f(sl, &sl.position(i)), tolerance),
+        })
     }
 
     pub fn occupied_seats(&self) -> usize {
-        self.layout
-            .values()
-            .cloned()
-            .filter(Occupancy::is_occupied)
-            .count()
+        self.count(Occupancy::is_occupied)
     }
 }
 
@@ -152,21 +185,13 @@ impl FromStr for SeatLayout {
     type Err = Box<dyn std::error::Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self {
-            layout: s
-                .trim()
-                .lines()
-                .enumerate()
-                .map(|(r, l)| {
-                    let mut p = Vec::new();
-                    for (c, o) in l.trim().char_indices() {
-                        p.push((Position(r, c), Occupancy::new(o)));
-                    }
-                    p
-                })
-                .flatten()
-                .collect(),
-        })
+        Ok(Grid::parse(s, |c| {
+            if ".L#".contains(c) {
+                Some(Occupancy::new(c))
+            } else {
+                None
+            }
+        })?)
     }
 }
 
@@ -175,24 +200,30 @@ fn parse_input_day11(input: &str) -> Result<SeatLayout, Box<dyn std::error::Erro
     input.parse()
 }
 
-#[aoc(day11, part1)]
-pub fn part1(layout: &SeatLayout) -> usize {
+/// Simulates `layout` until it settles, returning the number of occupied
+/// seats. The [`SEAT_TOLERANCE`] and `generations` parameters override the
+/// given tolerance and [`GENERATION_LIMIT`].
+fn settle(layout: &SeatLayout, neighbours: &Neighbours, tolerance: usize) -> Result<usize, Error> {
+    let seating = Seating {
+        tolerance: params::get_or(SEAT_TOLERANCE, tolerance)?,
+    };
+    let limit = params::get_or("generations", GENERATION_LIMIT)?;
     let mut lay = layout.clone();
-    while lay.update(4, SeatLayout::adjacent_occupied) {
-        // println!("After update:");
-        // println!("{}\n\n", lay);
+    match lay.run(neighbours, &seating, limit) {
+        Outcome::Stable { .. } => Ok(lay.occupied_seats()),
+        Outcome::Periodic { start, period } => Err(Error::Periodic { start, period }),
+        Outcome::GenerationLimit => Err(Error::GenerationLimit(limit)),
     }
-    lay.occupied_seats()
+}
+
+#[aoc(day11, part1)]
+pub fn part1(layout: &SeatLayout) -> Result<usize, Error> {
+    settle(layout, &layout.adjacency(), 4)
 }
 
 #[aoc(day11, part2)]
-pub fn part2(layout: &SeatLayout) -> usize {
-    let mut lay = layout.clone();
-    while lay.update(5, SeatLayout::first_occupied) {
-        // println!("After update:");
-        // println!("{}\n\n", lay);
-    }
-    lay.occupied_seats()
+pub fn part2(layout: &SeatLayout) -> Result<usize, Error> {
+    settle(layout, &layout.line_of_sight(), 5)
 }
 
 #[cfg(test)]
@@ -214,18 +245,133 @@ mod tests {
     fn parsing() {
         let parsed = parse_input_day11(SAMPLE).unwrap();
         println!("{}", parsed);
-        assert_eq!(parsed.layout.keys().len(), 100);
+        assert_eq!(parsed.cells().len(), 100);
+        assert_eq!((parsed.width(), parsed.height()), (10, 10));
+        assert_eq!(
+            parsed.to_string().replace('\n', ""),
+            SAMPLE.replace(char::is_whitespace, "")
+        );
+
+        assert!("L.\nL".parse::<SeatLayout>().is_err());
+        assert!("L.\nLx".parse::<SeatLayout>().is_err());
     }
 
     #[test]
     fn sample1() {
         let parsed = parse_input_day11(SAMPLE).unwrap();
-        assert_eq!(part1(&parsed), 37);
+        assert_eq!(part1(&parsed), Ok(37));
     }
 
     #[test]
     fn sample2() {
         let parsed = parse_input_day11(SAMPLE).unwrap();
-        assert_eq!(part2(&parsed), 26);
+        assert_eq!(part2(&parsed), Ok(26));
+    }
+
+    #[test]
+    fn oscillation() {
+        // With no tolerance every occupied seat is left again straight away,
+        // so the seats flip between empty and occupied forever.
+        let parsed = parse_input_day11(SAMPLE).unwrap();
+        params::set(SEAT_TOLERANCE, "0");
+        assert_eq!(
+            part1(&parsed),
+            Err(Error::Periodic {
+                start: 0,
+                period: 2
+            })
+        );
+
+        // day10's joltage tolerance does not affect the seating.
+        params::clear();
+        params::set("tolerance", "0");
+        assert_eq!(part2(&parsed), Ok(26));
+        params::set("generations", "2");
+        assert_eq!(part1(&parsed), Err(Error::GenerationLimit(2)));
+        params::set("generations", "x");
+        assert!(matches!(part2(&parsed), Err(Error::Param(_))));
+        params::clear();
+    }
+
+    #[test]
+    fn line_of_sight() {
+        let parsed = parse_input_day11(
+            ".......#.
+            ...#.....
+            .#.......
+            .........
+            ..#L....#
+            ....#....
+            .........
+            #........
+            ...#.....",
+        )
+        .unwrap();
+        assert_eq!(SeatLayout::first_occupied(&parsed, &Position(4, 3)), 8);
+
+        let parsed = parse_input_day11(
+            ".............
+            .L.L.#.#.#.#.
+            .............",
+        )
+        .unwrap();
+        assert_eq!(SeatLayout::first_occupied(&parsed, &Position(1, 1)), 0);
+        let visible = parsed.line_of_sight();
+        assert_eq!(
+            visible.of(&Position(1, 1)).collect::<Vec<_>>(),
+            vec![Position(1, 3)]
+        );
+        let i = parsed.index(&Position(1, 3)).unwrap();
+        assert_eq!(
+            visible
+                .around(parsed.cells(), i)
+                .count(|n| Occupancy::is_occupied(&n)),
+            1
+        );
+
+        let parsed = parse_input_day11(
+            ".##.##.
+            #.#.#.#
+            ##...##
+            ...L...
+            ##...##
+            #.#.#.#
+            .##.##.",
+        )
+        .unwrap();
+        assert_eq!(SeatLayout::first_occupied(&parsed, &Position(3, 3)), 0);
+        assert_eq!(parsed.line_of_sight().of(&Position(3, 3)).count(), 0);
+    }
+
+    #[test]
+    fn update_matches_step() {
+        let parsed = parse_input_day11(SAMPLE).unwrap();
+        let adjacent = parsed.adjacency();
+        let mut stepped = parsed.clone();
+        let mut updated = parsed.clone();
+        loop {
+            let changed = stepped.step(&adjacent, &Seating { tolerance: 4 });
+            assert_eq!(updated.update(4, SeatLayout::adjacent_occupied), changed);
+            assert_eq!(stepped, updated);
+            if !changed {
+                break;
+            }
+        }
+        assert_eq!(stepped.occupied_seats(), 37);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_step() {
+        let parsed = parse_input_day11(SAMPLE).unwrap();
+        let visible = parsed.line_of_sight();
+        let mut sequential = parsed.clone();
+        let mut parallel = parsed;
+        while sequential.step(&visible, &Seating { tolerance: 5 }) {
+            assert!(parallel.par_step(&visible, &Seating { tolerance: 5 }));
+            assert_eq!(sequential, parallel);
+        }
+        assert!(!parallel.par_step(&visible, &Seating { tolerance: 5 }));
+        assert_eq!(parallel.occupied_seats(), 26);
     }
 }
@@ -1,6 +1,8 @@
 use aoc_runner_derive::{aoc, aoc_generator};
 use std::{num::ParseIntError, str::FromStr};
 
+use crate::records::records;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     Byr,
@@ -176,10 +178,7 @@ impl FromStr for Passport {
 
 #[aoc_generator(day4)]
 fn parse_input_day4(input: &str) -> Result<Vec<Passport>, ParseIntError> {
-    Ok(input
-        .split_terminator("\n\n")
-        .filter_map(|p| p.parse().ok())
-        .collect())
+    Ok(records(input).filter_map(|p| p.parse().ok()).collect())
 }
 
 #[aoc(day4, part1)]
@@ -255,7 +254,8 @@ mod tests {
     #[test]
     fn newline_split() {
         let provided = include_str!("../input/2020/day4.txt");
-        assert_eq!(provided.split_terminator("\n\n").count(), 279);
+        assert_eq!(records(provided).count(), 279);
+        assert_eq!(records(&provided.replace('\n', "\r\n")).count(), 279);
     }
 
     #[test]
@@ -1,29 +1,83 @@
 use aoc_runner_derive::{aoc, aoc_generator};
-use std::collections::HashMap;
-use std::num::ParseIntError;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+use crate::records::records;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// An answer outside of the configured alphabet, as (group, person,
+    /// answer), with zero-based group and person indices.
+    UnknownAnswer(usize, usize, char),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownAnswer(group, person, answer) => write!(
+                f,
+                "Unknown answer {:?} from person {} in group {}",
+                answer, person, group
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The set of valid answers to the customs declaration form.
+#[derive(Debug, Clone)]
+pub struct Alphabet(HashSet<char>);
+
+impl Alphabet {
+    pub fn new(answers: impl IntoIterator<Item = char>) -> Self {
+        Self(answers.into_iter().collect())
+    }
+
+    pub fn contains(&self, answer: char) -> bool {
+        self.0.contains(&answer)
+    }
+}
+
+impl Default for Alphabet {
+    fn default() -> Self {
+        Self::new('a'..='z')
+    }
+}
 
 #[derive(Debug)]
 pub struct Histogram {
-    pub answers: HashMap<char, u8>,
-    pub people: u8,
+    pub answers: HashMap<char, usize>,
+    pub people: usize,
 }
 
-#[aoc_generator(day6)]
-fn parse_input_day6(input: &str) -> Result<Vec<Histogram>, ParseIntError> {
-    Ok(input
-        .trim()
-        .split_terminator("\n\n")
-        .map(|l| {
+/// Parses blank-line separated groups, with one person per line, rejecting
+/// any answer not in `alphabet`.
+pub fn parse_groups(input: &str, alphabet: &Alphabet) -> Result<Vec<Histogram>, Error> {
+    records(input)
+        .enumerate()
+        .map(|(group, record)| {
             let mut answers = HashMap::new();
-            for c in l.chars().filter(|c| c.is_alphabetic()) {
-                *answers.entry(c).or_insert(0) += 1;
-            }
-            Histogram {
-                answers,
-                people: l.lines().count() as u8,
+            let mut people = 0;
+
+            for (person, line) in record.lines().enumerate() {
+                for c in line.trim().chars() {
+                    if !alphabet.contains(c) {
+                        return Err(Error::UnknownAnswer(group, person, c));
+                    }
+                    *answers.entry(c).or_insert(0) += 1;
+                }
+                people += 1;
             }
+
+            Ok(Histogram { answers, people })
         })
-        .collect())
+        .collect()
+}
+
+#[aoc_generator(day6)]
+fn parse_input_day6(input: &str) -> Result<Vec<Histogram>, Error> {
+    parse_groups(input, &Alphabet::default())
 }
 
 #[aoc(day6, part1)]
@@ -81,6 +135,35 @@ mod tests {
         assert_eq!(part1(&parsed), 6);
     }
 
+    #[test]
+    fn crlf_and_whitespace_separators() {
+        let parsed = parse_input_day6("abc\r\n  \r\na\r\nb\r\n\r\n").unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].people, 2);
+        assert_eq!(part1(&parsed), 5);
+    }
+
+    #[test]
+    fn large_group() {
+        let input = "ab\n".repeat(300) + "a";
+        let parsed = parse_input_day6(&input).unwrap();
+        assert_eq!(parsed[0].people, 301);
+        assert_eq!(part1(&parsed), 2);
+        assert_eq!(part2(&parsed), 1);
+    }
+
+    #[test]
+    fn unknown_answer() {
+        assert_eq!(
+            parse_input_day6("ab\n\nc\nd3").unwrap_err(),
+            Error::UnknownAnswer(1, 1, '3')
+        );
+
+        let alphabet = Alphabet::new("abcd0123".chars());
+        let parsed = parse_groups("ab\n\nc\nd3", &alphabet).unwrap();
+        assert_eq!(part1(&parsed), 5);
+    }
+
     #[test]
     fn sample3() {
         let parsed = parse_input_day6(SAMPLE).unwrap();
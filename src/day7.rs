@@ -1,49 +1,91 @@
 use aoc_runner_derive::{aoc, aoc_generator};
-use std::{collections::HashMap, str::FromStr};
-use std::{collections::VecDeque, num::ParseIntError};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::{io, str::FromStr};
 
 use nom::branch::alt;
-use nom::bytes::complete::tag;
-use nom::character::complete::{alpha1, char as ch, digit1};
-use nom::combinator::map;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::{char as ch, digit1, space0, space1};
+use nom::combinator::{all_consuming, map, map_res, opt, verify};
 use nom::multi::separated_list1;
 use nom::sequence::tuple;
 use nom::{Finish, IResult};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct BagColor(String, String);
+use crate::params;
+
+/// A bag color made up of any number of words, such as `shiny gold` or
+/// `pale blue-green`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BagColor(Vec<String>);
 
 impl BagColor {
     pub fn new(description: &str, color: &str) -> Self {
-        Self(description.to_owned(), color.to_owned())
+        Self::from_words(vec![description, color])
+    }
+
+    pub fn from_words<'a>(words: impl IntoIterator<Item = &'a str>) -> Self {
+        Self(words.into_iter().map(str::to_owned).collect())
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl Display for BagColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join(" "))
+    }
+}
+
+impl FromStr for BagColor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (_, color) = all_consuming(color)(s.trim()).finish().map_err(drop)?;
+
+        Ok(color)
     }
 }
 
+fn word(input: &str) -> IResult<&str, &str> {
+    verify(
+        take_while1(|c: char| c.is_alphanumeric() || c == '-'),
+        |w: &str| w != "bag" && w != "bags",
+    )(input)
+}
+
+fn bag_or_bags(input: &str) -> IResult<&str, &str> {
+    alt((tag("bags"), tag("bag")))(input)
+}
+
 fn color(input: &str) -> IResult<&str, BagColor> {
-    let (input, (desc, _, color)) = tuple((alpha1, ch(' '), alpha1))(input)?;
-    Ok((input, BagColor::new(desc, color)))
+    map(separated_list1(space1, word), BagColor::from_words)(input)
 }
 
 fn content_item(input: &str) -> IResult<&str, (BagColor, usize)> {
-    let single = map(tuple((tag("1 "), color, tag(" bag"))), |(_, color, _)| {
-        (color, 1)
-    });
-    let multi = map(
-        tuple((digit1::<&str, _>, ch(' '), color, tag(" bags"))),
-        |(cnt, _, color, _)| {
-            let cnt: usize = cnt.parse().unwrap();
-            (color, cnt)
-        },
-    );
-
-    alt((single, multi))(input)
+    map(
+        tuple((
+            map_res(digit1, str::parse),
+            space1,
+            color,
+            space1,
+            bag_or_bags,
+        )),
+        |(cnt, _, color, _, _)| (color, cnt),
+    )(input)
 }
 
 fn contents(input: &str) -> IResult<&str, HashMap<BagColor, usize>> {
-    let non_empty = map(separated_list1(tag(", "), content_item), |data| {
-        data.into_iter().collect()
-    });
-    let empty = map(tag("no other bags"), |_| HashMap::new());
+    let non_empty = map(
+        separated_list1(tuple((space0, ch(','), space0)), content_item),
+        |data| data.into_iter().collect(),
+    );
+    let empty = map(
+        tuple((tag("no"), space1, tag("other"), space1, bag_or_bags)),
+        |_| HashMap::new(),
+    );
 
     alt((non_empty, empty))(input)
 }
@@ -56,8 +98,17 @@ pub struct Bag {
 
 impl Bag {
     pub fn parse(input: &str) -> IResult<&str, Bag> {
-        let (input, (color, _, contents, _)) =
-            tuple((color, tag(" bags contain "), contents, ch('.')))(input)?;
+        let (input, (color, _, _, _, _, _, contents, _, _)) = tuple((
+            color,
+            space1,
+            bag_or_bags,
+            space1,
+            alt((tag("contains"), tag("contain"))),
+            space1,
+            contents,
+            space0,
+            opt(ch('.')),
+        ))(input)?;
         Ok((input, Bag { color, contents }))
     }
 }
@@ -66,82 +117,700 @@ impl FromStr for Bag {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (_, entry) = Self::parse(s.trim()).finish().map_err(drop)?;
+        let (_, entry) = all_consuming(Self::parse)(s.trim())
+            .finish()
+            .map_err(drop)?;
 
         Ok(entry)
     }
 }
 
-#[derive(Debug, Default, Clone)]
-struct BagWithIndirect {
-    contents: HashMap<BagColor, usize>,
+/// Writes the rule in canonical form, with the contents sorted by color.
+impl Display for Bag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bags contain ", self.color)?;
+
+        if self.contents.is_empty() {
+            return write!(f, "no other bags.");
+        }
+
+        let mut contents: Vec<_> = self.contents.iter().collect();
+        contents.sort();
+        for (i, (color, cnt)) in contents.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            let bags = if *cnt == 1 { "bag" } else { "bags" };
+            write!(f, "{} {} {}", cnt, color, bags)?;
+        }
+        write!(f, ".")
+    }
 }
 
-impl BagWithIndirect {
-    pub fn contains(&self, bag: &BagColor) -> bool {
-        self.contents.contains_key(bag)
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A rule refers to a bag that has no rule of its own.
+    UndefinedBag(BagColor),
+    /// The rules contain a cycle, listed in containment order.
+    Cycle(Vec<BagColor>),
+    /// The number of bags inside this bag does not fit in a `usize`.
+    Overflow(BagColor),
+    /// The rule cannot be removed while other bags contain this bag.
+    Referenced(BagColor),
+    Param(params::Error),
+    /// Line `line`, counted from 1, is not a valid rule.
+    InvalidRule {
+        line: usize,
+        rule: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndefinedBag(color) => write!(f, "No rule for bag {:?}", color),
+            Self::Cycle(colors) => write!(f, "Bags contain each other: {:?}", colors),
+            Self::Overflow(color) => write!(f, "Too many bags inside {:?}", color),
+            Self::Referenced(color) => write!(f, "Bag {:?} is inside other bags", color),
+            Self::Param(e) => write!(f, "{}", e),
+            Self::InvalidRule { line, rule } => write!(f, "{}: Invalid rule {:?}", line, rule),
+        }
     }
+}
+
+impl std::error::Error for Error {}
 
-    pub fn total_bags(&self) -> usize {
-        self.contents.iter().map(|(_, cnt)| *cnt).sum()
+impl From<params::Error> for Error {
+    fn from(e: params::Error) -> Self {
+        Self::Param(e)
     }
 }
 
-fn build_mappings<'a>(bags: impl Iterator<Item = &'a Bag>) -> HashMap<BagColor, BagWithIndirect> {
-    let mut direct_only: HashMap<_, _> = bags
-        .map(|bag| (bag.color.clone(), bag.contents.clone()))
-        .collect();
+/// How to treat bags that are referenced by a rule but never defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Report undefined bags as [`Error::UndefinedBag`].
+    Strict,
+    /// Treat undefined bags as empty.
+    Lenient,
+}
 
-    let mut queue: VecDeque<_> = direct_only.iter().map(|(color, _)| color.clone()).collect();
+#[derive(Debug, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
 
-    let mut with_indirect: HashMap<BagColor, BagWithIndirect> = HashMap::new();
+/// Orders the bags such that every bag comes after all the bags it contains.
+fn topological_order(
+    rules: &HashMap<BagColor, HashMap<BagColor, usize>>,
+    mode: Mode,
+) -> Result<Vec<&BagColor>, Error> {
+    let mut visits: HashMap<&BagColor, Visit> = HashMap::new();
+    let mut order = Vec::with_capacity(rules.len());
 
-    while let Some(bag) = queue.pop_front() {
-        if with_indirect.get(&bag).is_some() {
+    for (root, contents) in rules.iter() {
+        if visits.contains_key(root) {
             continue;
         }
 
-        let directs = direct_only.entry(bag.clone()).or_default();
-        if directs
-            .keys()
-            .all(|color| with_indirect.get(color).is_some())
-        {
-            let mut contents = directs.clone();
+        visits.insert(root, Visit::InProgress);
+        let mut stack = vec![(root, contents.keys())];
+
+        while let Some((color, children)) = stack.last_mut() {
+            let color = *color;
 
-            for (child, child_cnt) in directs.iter() {
-                for (color, cnt) in with_indirect.get(child).unwrap().contents.iter() {
-                    *contents.entry(color.clone()).or_default() += cnt * child_cnt;
+            match children.next() {
+                Some(child) => match visits.get(child) {
+                    Some(Visit::Done) => {}
+                    Some(Visit::InProgress) => {
+                        let start = stack.iter().position(|(c, _)| *c == child).unwrap();
+                        return Err(Error::Cycle(
+                            stack[start..].iter().map(|(c, _)| (*c).clone()).collect(),
+                        ));
+                    }
+                    None => match (rules.get_key_value(child), mode) {
+                        (Some((child, contents)), _) => {
+                            visits.insert(child, Visit::InProgress);
+                            stack.push((child, contents.keys()));
+                        }
+                        (None, Mode::Lenient) => {
+                            visits.insert(child, Visit::Done);
+                            order.push(child);
+                        }
+                        (None, Mode::Strict) => return Err(Error::UndefinedBag(child.clone())),
+                    },
+                },
+                None => {
+                    visits.insert(color, Visit::Done);
+                    order.push(color);
+                    stack.pop();
                 }
             }
+        }
+    }
+
+    Ok(order)
+}
+
+/// A bag directly inside, or directly around, another bag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relation {
+    pub color: BagColor,
+    /// How many of the inner bag the outer bag holds.
+    pub count: usize,
+}
+
+/// Every bag that eventually contains a given bag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ancestors {
+    pub colors: HashSet<BagColor>,
+}
+
+impl Ancestors {
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+}
+
+/// Everything nested inside a given bag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Contents {
+    /// Total number of bags inside, at any level of nesting.
+    pub total_bags: usize,
+    /// Number of levels of bags inside, `0` for an empty bag.
+    pub max_depth: usize,
+}
+
+/// A validated set of rules, free of cycles and undefined bags, that can be
+/// queried for any bag color.
+///
+/// Rules can be inserted, replaced and removed one at a time. Query results
+/// are cached, and an edit only invalidates the cached results it affects.
+#[derive(Debug, Clone)]
+pub struct BagRules {
+    mode: Mode,
+    children: HashMap<BagColor, HashMap<BagColor, usize>>,
+    parents: HashMap<BagColor, HashMap<BagColor, usize>>,
+    /// Bags without a rule of their own, treated as empty in
+    /// [`Mode::Lenient`].
+    implicit: HashSet<BagColor>,
+    contents_cache: RefCell<HashMap<BagColor, Contents>>,
+    ancestors_cache: RefCell<HashMap<BagColor, HashSet<BagColor>>>,
+}
+
+/// The bags actually held, dropping any `0 <color> bags` entries so they do
+/// not count as containment.
+fn held(contents: &HashMap<BagColor, usize>) -> HashMap<BagColor, usize> {
+    contents
+        .iter()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(color, &count)| (color.clone(), count))
+        .collect()
+}
+
+impl BagRules {
+    /// Builds the rules from `bags`. Entries holding 0 bags are dropped, so
+    /// [`BagRules::get`] returns rules without them.
+    pub fn new<'a>(bags: impl IntoIterator<Item = &'a Bag>, mode: Mode) -> Result<Self, Error> {
+        let mut children: HashMap<_, _> = bags
+            .into_iter()
+            .map(|bag| (bag.color.clone(), held(&bag.contents)))
+            .collect();
+
+        let implicit: HashSet<_> = topological_order(&children, mode)?
+            .into_iter()
+            .filter(|color| !children.contains_key(*color))
+            .cloned()
+            .collect();
+        children.extend(implicit.iter().map(|color| (color.clone(), HashMap::new())));
+
+        let mut parents: HashMap<BagColor, HashMap<BagColor, usize>> = children
+            .keys()
+            .map(|color| (color.clone(), HashMap::new()))
+            .collect();
+        for (parent, contents) in children.iter() {
+            for (child, count) in contents.iter() {
+                parents
+                    .get_mut(child)
+                    .unwrap()
+                    .insert(parent.clone(), *count);
+            }
+        }
+
+        Ok(Self {
+            mode,
+            children,
+            parents,
+            implicit,
+            contents_cache: RefCell::default(),
+            ancestors_cache: RefCell::default(),
+        })
+    }
+
+    /// The rule for `color`, if one has been defined.
+    pub fn get(&self, color: &BagColor) -> Option<Bag> {
+        if self.implicit.contains(color) {
+            return None;
+        }
+
+        self.children.get(color).map(|contents| Bag {
+            color: color.clone(),
+            contents: contents.clone(),
+        })
+    }
+
+    /// Inserts or replaces the rule for `bag.color`, returning the previous
+    /// rule. The rules are left untouched if the new rule would introduce a
+    /// cycle, or refer to an undefined bag in [`Mode::Strict`].
+    pub fn insert(&mut self, bag: Bag) -> Result<Option<Bag>, Error> {
+        let bag = Bag {
+            contents: held(&bag.contents),
+            color: bag.color,
+        };
+        for child in bag.contents.keys() {
+            if self.mode == Mode::Strict
+                && (!self.children.contains_key(child) || self.implicit.contains(child))
+                && *child != bag.color
+            {
+                return Err(Error::UndefinedBag(child.clone()));
+            }
+        }
+        if let Some(cycle) = self.path_to(&bag) {
+            return Err(Error::Cycle(cycle));
+        }
+
+        let previous = self.get(&bag.color);
+        let old_contents = self.unlink(&bag.color);
+
+        for (child, count) in bag.contents.iter() {
+            if !self.children.contains_key(child) {
+                self.children.insert(child.clone(), HashMap::new());
+                self.implicit.insert(child.clone());
+            }
+            self.parents
+                .entry(child.clone())
+                .or_default()
+                .insert(bag.color.clone(), *count);
+        }
+        self.parents.entry(bag.color.clone()).or_default();
+        self.implicit.remove(&bag.color);
+        self.children
+            .insert(bag.color.clone(), bag.contents.clone());
+
+        self.invalidate(&bag.color, old_contents.keys().chain(bag.contents.keys()));
+        Ok(previous)
+    }
+
+    /// Removes the rule for `color`, returning it. In [`Mode::Strict`] a rule
+    /// still referred to by other rules cannot be removed, while in
+    /// [`Mode::Lenient`] the bag is treated as empty from then on.
+    pub fn remove(&mut self, color: &BagColor) -> Result<Option<Bag>, Error> {
+        let previous = match self.get(color) {
+            Some(bag) => bag,
+            None => return Ok(None),
+        };
+
+        let referenced = !self.parents[color].is_empty();
+        if referenced && self.mode == Mode::Strict {
+            return Err(Error::Referenced(color.clone()));
+        }
 
-            with_indirect.insert(bag, BagWithIndirect { contents });
+        let old_contents = self.unlink(color);
+        self.invalidate(color, old_contents.keys());
+
+        if referenced {
+            self.implicit.insert(color.clone());
         } else {
-            queue.push_back(bag)
+            self.children.remove(color);
+            self.parents.remove(color);
+            self.ancestors_cache.get_mut().remove(color);
+        }
+        Ok(Some(previous))
+    }
+
+    /// Detaches `color` from its children, dropping implicit children that
+    /// are no longer referenced, and returns its previous contents.
+    fn unlink(&mut self, color: &BagColor) -> HashMap<BagColor, usize> {
+        let contents = self.children.remove(color).unwrap_or_default();
+
+        for child in contents.keys() {
+            let parents = self.parents.get_mut(child).unwrap();
+            parents.remove(color);
+            if parents.is_empty() && self.implicit.remove(child) {
+                self.parents.remove(child);
+                self.children.remove(child);
+                self.contents_cache.get_mut().remove(child);
+                self.ancestors_cache.get_mut().remove(child);
+            }
+        }
+
+        self.children.insert(color.clone(), HashMap::new());
+        contents
+    }
+
+    /// Drops cached results affected by a change to the rule for `color`,
+    /// whose contents were or are now `children`.
+    fn invalidate<'a>(&mut self, color: &BagColor, children: impl Iterator<Item = &'a BagColor>) {
+        // Any cached bag containing `color` had its contents evaluated, and
+        // cached, along with those of `color`.
+        if self.contents_cache.get_mut().remove(color).is_some() {
+            let ancestors = Self::reachable_from(&self.parents, self.parents[color].keys());
+            let cache = self.contents_cache.get_mut();
+            for ancestor in ancestors.iter() {
+                cache.remove(ancestor);
+            }
+        }
+
+        let children: Vec<_> = children
+            .filter(|c| self.children.contains_key(*c))
+            .collect();
+        let cache = self.ancestors_cache.get_mut();
+        if !cache.is_empty() {
+            for descendant in Self::reachable_from(&self.children, children.into_iter()) {
+                cache.remove(&descendant);
+            }
         }
     }
 
-    with_indirect
+    /// Finds a path of rules leading from `bag` back to `bag.color`, which
+    /// inserting `bag` would turn into a cycle.
+    fn path_to(&self, bag: &Bag) -> Option<Vec<BagColor>> {
+        let target = &bag.color;
+        let mut previous: HashMap<&BagColor, &BagColor> = HashMap::new();
+        let mut queue: VecDeque<_> = bag.contents.keys().collect();
+        for child in bag.contents.keys() {
+            previous.insert(child, target);
+        }
+
+        while let Some(color) = queue.pop_front() {
+            if color == target {
+                let mut path = vec![];
+                let mut current = previous[color];
+                while current != target {
+                    path.push(current.clone());
+                    current = previous[current];
+                }
+                path.push(target.clone());
+                path.reverse();
+                return Some(path);
+            }
+
+            for child in self.children.get(color).into_iter().flat_map(|c| c.keys()) {
+                if !previous.contains_key(child) {
+                    previous.insert(child, color);
+                    queue.push_back(child);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn relations(
+        graph: &HashMap<BagColor, HashMap<BagColor, usize>>,
+        color: &BagColor,
+    ) -> Result<Vec<Relation>, Error> {
+        let mut relations: Vec<_> = graph
+            .get(color)
+            .ok_or_else(|| Error::UndefinedBag(color.clone()))?
+            .iter()
+            .map(|(color, count)| Relation {
+                color: color.clone(),
+                count: *count,
+            })
+            .collect();
+        relations.sort_by(|a, b| a.color.cmp(&b.color));
+        Ok(relations)
+    }
+
+    /// Bags directly containing `color`, with how many of `color` each holds.
+    pub fn parents(&self, color: &BagColor) -> Result<Vec<Relation>, Error> {
+        Self::relations(&self.parents, color)
+    }
+
+    /// Bags directly inside `color`.
+    pub fn children(&self, color: &BagColor) -> Result<Vec<Relation>, Error> {
+        Self::relations(&self.children, color)
+    }
+
+    /// Bags reachable by following `graph` from any of `colors`, including
+    /// `colors` themselves.
+    fn reachable_from<'a>(
+        graph: &HashMap<BagColor, HashMap<BagColor, usize>>,
+        colors: impl Iterator<Item = &'a BagColor>,
+    ) -> HashSet<BagColor> {
+        let mut reached = HashSet::new();
+        let mut queue: Vec<_> = colors.collect();
+
+        while let Some(color) = queue.pop() {
+            if reached.insert(color.clone()) {
+                queue.extend(graph[color].keys());
+            }
+        }
+
+        reached
+    }
+
+    /// Bags reachable from `color` by following `graph`, excluding `color`
+    /// itself unless it is part of a cycle.
+    fn reachable(
+        graph: &HashMap<BagColor, HashMap<BagColor, usize>>,
+        color: &BagColor,
+    ) -> Result<HashSet<BagColor>, Error> {
+        let children = graph
+            .get(color)
+            .ok_or_else(|| Error::UndefinedBag(color.clone()))?;
+
+        Ok(Self::reachable_from(graph, children.keys()))
+    }
+
+    /// Bags that eventually contain `color`.
+    pub fn ancestors(&self, color: &BagColor) -> Result<Ancestors, Error> {
+        if let Some(colors) = self.ancestors_cache.borrow().get(color) {
+            return Ok(Ancestors {
+                colors: colors.clone(),
+            });
+        }
+
+        let colors = Self::reachable(&self.parents, color)?;
+        self.ancestors_cache
+            .borrow_mut()
+            .insert(color.clone(), colors.clone());
+        Ok(Ancestors { colors })
+    }
+
+    /// Everything nested inside `color`.
+    ///
+    /// Only the bags inside `color` are visited, each of them once, and the
+    /// counts are checked for overflow.
+    pub fn contents(&self, color: &BagColor) -> Result<Contents, Error> {
+        if !self.children.contains_key(color) {
+            return Err(Error::UndefinedBag(color.clone()));
+        }
+
+        let mut memo = self.contents_cache.borrow_mut();
+        let mut stack = vec![(color, false)];
+
+        while let Some((bag, expanded)) = stack.pop() {
+            if memo.contains_key(bag) {
+                continue;
+            }
+
+            let children = &self.children[bag];
+            if !expanded {
+                // Revisit `bag` once all of its children have been evaluated
+                stack.push((bag, true));
+                stack.extend(
+                    children
+                        .keys()
+                        .filter(|child| !memo.contains_key(*child))
+                        .map(|child| (child, false)),
+                );
+                continue;
+            }
+
+            let mut contents = Contents::default();
+            for (child, count) in children.iter() {
+                let inner = memo[child];
+                contents.total_bags = inner
+                    .total_bags
+                    .checked_add(1)
+                    .and_then(|n| n.checked_mul(*count))
+                    .and_then(|n| n.checked_add(contents.total_bags))
+                    .ok_or_else(|| Error::Overflow(bag.clone()))?;
+                contents.max_depth = contents.max_depth.max(inner.max_depth + 1);
+            }
+            memo.insert(bag.clone(), contents);
+        }
+
+        Ok(memo[color])
+    }
+}
+
+/// Part of the containment graph to highlight in an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight<'a> {
+    None,
+    /// The bag and everything it eventually contains.
+    ContentsOf(&'a BagColor),
+    /// The bag and every bag eventually containing it.
+    ContainersOf(&'a BagColor),
+}
+
+/// Writes the containment graph of a [`BagRules`] in various formats.
+pub struct Export<'a> {
+    rules: &'a BagRules,
+    highlighted: HashSet<BagColor>,
+}
+
+impl BagRules {
+    pub fn export(&self, highlight: Highlight) -> Result<Export<'_>, Error> {
+        let highlighted = match highlight {
+            Highlight::None => HashSet::new(),
+            Highlight::ContentsOf(color) => {
+                let mut colors = Self::reachable(&self.children, color)?;
+                colors.insert(color.clone());
+                colors
+            }
+            Highlight::ContainersOf(color) => {
+                let mut colors = Self::reachable(&self.parents, color)?;
+                colors.insert(color.clone());
+                colors
+            }
+        };
+
+        Ok(Export {
+            rules: self,
+            highlighted,
+        })
+    }
+}
+
+impl<'a> Export<'a> {
+    /// All rules, sorted by color, with their sorted contents.
+    fn sorted(&self) -> Vec<(&'a BagColor, Vec<(&'a BagColor, usize)>)> {
+        let mut rules: Vec<_> = self
+            .rules
+            .children
+            .iter()
+            .map(|(color, contents)| {
+                let mut contents: Vec<_> = contents.iter().map(|(c, n)| (c, *n)).collect();
+                contents.sort();
+                (color, contents)
+            })
+            .collect();
+        rules.sort();
+        rules
+    }
+
+    fn is_highlighted(&self, color: &BagColor) -> bool {
+        self.highlighted.contains(color)
+    }
+
+    /// Writes the graph as Graphviz DOT, with edges labelled by count.
+    pub fn write_dot(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let rules = self.sorted();
+
+        writeln!(w, "digraph bags {{")?;
+        for (color, _) in rules.iter() {
+            write!(w, "    {:?}", color.to_string())?;
+            if self.is_highlighted(color) {
+                write!(w, " [color=red, penwidth=2]")?;
+            }
+            writeln!(w, ";")?;
+        }
+        for (color, contents) in rules.iter() {
+            for (child, count) in contents.iter() {
+                write!(
+                    w,
+                    "    {:?} -> {:?} [label=\"{}\"",
+                    color.to_string(),
+                    child.to_string(),
+                    count
+                )?;
+                if self.is_highlighted(color) && self.is_highlighted(child) {
+                    write!(w, ", color=red, penwidth=2")?;
+                }
+                writeln!(w, "];")?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes the graph as a JSON adjacency list.
+    pub fn write_json(&self, w: &mut impl io::Write) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"bags\": [")?;
+
+        let rules = self.sorted();
+        for (i, (color, contents)) in rules.iter().enumerate() {
+            write!(
+                w,
+                "    {{\"color\": {}, \"highlighted\": {}, \"contents\": [",
+                json_string(&color.to_string()),
+                self.is_highlighted(color)
+            )?;
+            for (j, (child, count)) in contents.iter().enumerate() {
+                if j > 0 {
+                    write!(w, ", ")?;
+                }
+                write!(
+                    w,
+                    "{{\"color\": {}, \"count\": {}}}",
+                    json_string(&child.to_string()),
+                    count
+                )?;
+            }
+            writeln!(w, "]}}{}", if i + 1 < rules.len() { "," } else { "" })?;
+        }
+
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Number of bag colors that can eventually contain a `color` bag.
+pub fn count_containers(bags: &[Bag], color: &BagColor) -> Result<usize, Error> {
+    Ok(BagRules::new(bags, Mode::Strict)?.ancestors(color)?.len())
+}
+
+/// Number of bags required inside a `color` bag.
+pub fn count_contents(bags: &[Bag], color: &BagColor) -> Result<usize, Error> {
+    Ok(BagRules::new(bags, Mode::Strict)?
+        .contents(color)?
+        .total_bags)
 }
 
 #[aoc_generator(day7)]
-fn parse_input_day7(input: &str) -> Result<Vec<Bag>, ParseIntError> {
-    Ok(input.lines().map(|l| l.parse().unwrap()).collect())
+/// Parses one rule per line, skipping blank lines.
+fn parse_input_day7(input: &str) -> Result<Vec<Bag>, Error> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            l.parse().map_err(|()| Error::InvalidRule {
+                line: i + 1,
+                rule: l.trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// The bag both parts ask about, settable from the runner with
+/// `--param bag=<color>`. Defaults to shiny gold.
+fn target_bag() -> Result<BagColor, Error> {
+    Ok(params::get_or("bag", BagColor::new("shiny", "gold"))?)
 }
 
 #[aoc(day7, part1)]
-pub fn part1(bags: &[Bag]) -> usize {
-    build_mappings(bags.into_iter())
-        .values()
-        .filter(|bags| bags.contains(&BagColor::new("shiny", "gold")))
-        .count()
+pub fn part1(bags: &[Bag]) -> Result<usize, Error> {
+    count_containers(bags, &target_bag()?)
 }
 
 #[aoc(day7, part2)]
-pub fn part2(bags: &[Bag]) -> usize {
-    build_mappings(bags.into_iter())
-        .get(&BagColor::new("shiny", "gold"))
-        .expect("No such bag!")
-        .total_bags()
+pub fn part2(bags: &[Bag]) -> Result<usize, Error> {
+    count_contents(bags, &target_bag()?)
 }
 
 #[cfg(test)]
@@ -205,18 +874,485 @@ mod tests {
     #[test]
     fn sample1() {
         let parsed = parse_input_day7(SAMPLE).unwrap();
-        assert_eq!(part1(&parsed), 4);
+        assert_eq!(part1(&parsed), Ok(4));
     }
 
     #[test]
     fn sample1_part2() {
         let parsed = parse_input_day7(SAMPLE).unwrap();
-        assert_eq!(part2(&parsed), 32);
+        assert_eq!(part2(&parsed), Ok(32));
+    }
+
+    #[test]
+    fn bag_param() {
+        let parsed = parse_input_day7(SAMPLE).unwrap();
+        params::set("bag", "dark olive");
+        assert_eq!(part1(&parsed), Ok(5));
+        assert_eq!(part2(&parsed), Ok(7));
+
+        params::set("bag", "pale pink");
+        assert_eq!(
+            part1(&parsed),
+            Err(Error::UndefinedBag(BagColor::new("pale", "pink")))
+        );
+
+        params::set("bag", "shiny gold bags");
+        assert_eq!(
+            part2(&parsed),
+            Err(Error::Param(params::Error::Invalid {
+                key: "bag".to_owned(),
+                value: "shiny gold bags".to_owned()
+            }))
+        );
+        params::clear();
     }
 
     #[test]
     fn sample2_part2() {
         let parsed = parse_input_day7(SAMPLE2).unwrap();
-        assert_eq!(part2(&parsed), 126);
+        assert_eq!(part2(&parsed), Ok(126));
+    }
+
+    #[test]
+    fn cycle() {
+        let parsed = parse_input_day7(
+            "shiny gold bags contain 2 dark red bags.
+            dark red bags contain 1 light blue bag.
+            light blue bags contain 3 shiny gold bags.",
+        )
+        .unwrap();
+
+        match BagRules::new(&parsed, Mode::Lenient) {
+            Err(Error::Cycle(cycle)) => {
+                assert_eq!(cycle.len(), 3);
+                let start = cycle
+                    .iter()
+                    .position(|c| *c == BagColor::new("shiny", "gold"))
+                    .unwrap();
+                assert_eq!(cycle[(start + 1) % 3], BagColor::new("dark", "red"));
+                assert_eq!(cycle[(start + 2) % 3], BagColor::new("light", "blue"));
+            }
+            other => panic!("Expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn self_containing() {
+        let parsed = parse_input_day7("shiny gold bags contain 1 shiny gold bag.").unwrap();
+        assert_eq!(
+            part1(&parsed),
+            Err(Error::Cycle(vec![BagColor::new("shiny", "gold")]))
+        );
+    }
+
+    #[test]
+    fn undefined_bag() {
+        let parsed = parse_input_day7(
+            "shiny gold bags contain 2 dark red bags, 3 faded blue bags.
+            dark red bags contain no other bags.",
+        )
+        .unwrap();
+
+        assert_eq!(
+            part2(&parsed),
+            Err(Error::UndefinedBag(BagColor::new("faded", "blue")))
+        );
+
+        let rules = BagRules::new(&parsed, Mode::Lenient).unwrap();
+        assert_eq!(
+            rules.contents(&BagColor::new("shiny", "gold")).unwrap(),
+            Contents {
+                total_bags: 5,
+                max_depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn queries() {
+        let parsed = parse_input_day7(SAMPLE).unwrap();
+        let rules = BagRules::new(&parsed, Mode::Strict).unwrap();
+        let shiny_gold = BagColor::new("shiny", "gold");
+
+        assert_eq!(
+            rules.parents(&shiny_gold).unwrap(),
+            vec![
+                Relation {
+                    color: BagColor::new("bright", "white"),
+                    count: 1
+                },
+                Relation {
+                    color: BagColor::new("muted", "yellow"),
+                    count: 2
+                }
+            ]
+        );
+        assert_eq!(
+            rules.children(&shiny_gold).unwrap(),
+            vec![
+                Relation {
+                    color: BagColor::new("dark", "olive"),
+                    count: 1
+                },
+                Relation {
+                    color: BagColor::new("vibrant", "plum"),
+                    count: 2
+                }
+            ]
+        );
+
+        let ancestors = rules.ancestors(&shiny_gold).unwrap();
+        assert_eq!(ancestors.len(), 4);
+        assert!(ancestors.colors.contains(&BagColor::new("light", "red")));
+        assert!(rules
+            .ancestors(&BagColor::new("light", "red"))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            rules.contents(&shiny_gold).unwrap(),
+            Contents {
+                total_bags: 32,
+                max_depth: 2
+            }
+        );
+        assert_eq!(
+            count_contents(&parse_input_day7(SAMPLE2).unwrap(), &shiny_gold),
+            Ok(126)
+        );
+        assert_eq!(
+            rules.contents(&BagColor::new("pale", "pink")),
+            Err(Error::UndefinedBag(BagColor::new("pale", "pink")))
+        );
+    }
+
+    #[test]
+    fn export_dot() {
+        let parsed = parse_input_day7(
+            "shiny gold bags contain 2 dark red bags.
+            dark red bags contain no other bags.
+            light blue bags contain 1 shiny gold bag.",
+        )
+        .unwrap();
+        let rules = BagRules::new(&parsed, Mode::Strict).unwrap();
+        let shiny_gold = BagColor::new("shiny", "gold");
+
+        let mut dot = Vec::new();
+        rules
+            .export(Highlight::ContentsOf(&shiny_gold))
+            .unwrap()
+            .write_dot(&mut dot)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph bags {
+    \"dark red\" [color=red, penwidth=2];
+    \"light blue\";
+    \"shiny gold\" [color=red, penwidth=2];
+    \"light blue\" -> \"shiny gold\" [label=\"1\"];
+    \"shiny gold\" -> \"dark red\" [label=\"2\", color=red, penwidth=2];
+}
+"
+        );
+    }
+
+    #[test]
+    fn export_json() {
+        let parsed = parse_input_day7(
+            "shiny gold bags contain 2 dark red bags.
+            dark red bags contain no other bags.
+            light blue bags contain 1 shiny gold bag.",
+        )
+        .unwrap();
+        let rules = BagRules::new(&parsed, Mode::Strict).unwrap();
+
+        let mut json = Vec::new();
+        rules
+            .export(Highlight::ContainersOf(&BagColor::new("shiny", "gold")))
+            .unwrap()
+            .write_json(&mut json)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{
+  "bags": [
+    {"color": "dark red", "highlighted": false, "contents": []},
+    {"color": "light blue", "highlighted": true, "contents": [{"color": "shiny gold", "count": 1}]},
+    {"color": "shiny gold", "highlighted": true, "contents": [{"color": "dark red", "count": 2}]}
+  ]
+}
+"#
+        );
+
+        assert!(matches!(
+            rules.export(Highlight::ContentsOf(&BagColor::new("pale", "pink"))),
+            Err(Error::UndefinedBag(_))
+        ));
+    }
+
+    #[test]
+    fn deep_chain() {
+        let colors: Vec<_> = (0..100_000)
+            .map(|i| BagColor::new("chain", &i.to_string()))
+            .collect();
+        let bags: Vec<_> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| Bag {
+                color: color.clone(),
+                contents: colors
+                    .get(i + 1)
+                    .map(|c| (c.clone(), 1))
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+
+        let rules = BagRules::new(&bags, Mode::Strict).unwrap();
+        assert_eq!(
+            rules.contents(&colors[0]),
+            Ok(Contents {
+                total_bags: 99_999,
+                max_depth: 99_999
+            })
+        );
+        assert_eq!(rules.ancestors(&colors[99_999]).unwrap().len(), 99_999);
+    }
+
+    #[test]
+    fn overflow() {
+        let bags: Vec<_> = (0..100)
+            .map(|i| Bag {
+                color: BagColor::new("chain", &i.to_string()),
+                contents: vec![(BagColor::new("chain", &(i + 1).to_string()), 2)]
+                    .into_iter()
+                    .filter(|_| i < 99)
+                    .collect(),
+            })
+            .collect();
+
+        let rules = BagRules::new(&bags, Mode::Strict).unwrap();
+        assert!(rules.contents(&BagColor::new("chain", "90")).is_ok());
+        assert!(matches!(
+            rules.contents(&BagColor::new("chain", "0")),
+            Err(Error::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn parse_tolerant() {
+        assert_eq!(
+            Bag::from_str(
+                "shiny dark gold bags contain 1 pale blue-green bags,  0 dull red bags,3 x bag"
+            ),
+            Ok(Bag {
+                color: BagColor::from_words(vec!["shiny", "dark", "gold"]),
+                contents: vec![
+                    (BagColor::new("pale", "blue-green"), 1),
+                    (BagColor::new("dull", "red"), 0),
+                    (BagColor::from_words(vec!["x"]), 3)
+                ]
+                .into_iter()
+                .collect()
+            })
+        );
+        assert_eq!(
+            Bag::from_str("faded  blue bag contains no other bag"),
+            Ok(Bag {
+                color: BagColor::new("faded", "blue"),
+                contents: HashMap::new()
+            })
+        );
+        assert_eq!(Bag::from_str("shiny gold bags contain bags."), Err(()));
+        assert_eq!(
+            Bag::from_str("shiny gold bags contain 1 red bag. extra"),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn zero_count_contents() {
+        let parsed = parse_input_day7(
+            "bright white bags contain 0 shiny gold bags, 2 faded blue bags.
+            light red bags contain 1 bright white bag.
+            shiny gold bags contain 0 shiny gold bags.
+            faded blue bags contain no other bags.",
+        )
+        .unwrap();
+        let shiny_gold = BagColor::new("shiny", "gold");
+        assert_eq!(count_containers(&parsed, &shiny_gold), Ok(0));
+        assert_eq!(count_contents(&parsed, &shiny_gold), Ok(0));
+
+        let mut rules = BagRules::new(&parsed, Mode::Strict).unwrap();
+        assert_eq!(rules.parents(&shiny_gold), Ok(vec![]));
+        assert_eq!(
+            rules
+                .get(&BagColor::new("bright", "white"))
+                .unwrap()
+                .contents,
+            vec![(BagColor::new("faded", "blue"), 2)]
+                .into_iter()
+                .collect()
+        );
+
+        rules
+            .insert("faded blue bags contain 0 light red bags".parse().unwrap())
+            .unwrap();
+        assert_eq!(rules.ancestors(&shiny_gold).map(|a| a.len()), Ok(0));
+        assert_eq!(
+            rules
+                .ancestors(&BagColor::new("faded", "blue"))
+                .map(|a| a.len()),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let bag = Bag::from_str("light red bags contain 2 muted yellow bags, 1 bright white bag.")
+            .unwrap();
+        assert_eq!(
+            bag.to_string(),
+            "light red bags contain 1 bright white bag, 2 muted yellow bags."
+        );
+
+        for line in SAMPLE.lines().chain(vec![
+            "shiny dark gold bags contain 1 pale blue-green bag, 0 dull red bags.",
+            "dotted black bags contain no other bags.",
+        ]) {
+            let bag = Bag::from_str(line).unwrap();
+            assert_eq!(Bag::from_str(&bag.to_string()), Ok(bag));
+        }
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert_eq!(
+            parse_input_day7("x\n\ny"),
+            Err(Error::InvalidRule {
+                line: 1,
+                rule: "x".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_input_day7("faded blue bags contain no other bags.\n\n  shiny gold bags\n"),
+            Err(Error::InvalidRule {
+                line: 3,
+                rule: "shiny gold bags".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_input_day7("\nfaded blue bags contain no other bags.\n  \n")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn parse_color() {
+        assert_eq!(
+            BagColor::from_str(" shiny  dark gold "),
+            Ok(BagColor::from_words(vec!["shiny", "dark", "gold"]))
+        );
+        assert_eq!(BagColor::from_str("shiny gold bags"), Err(()));
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let parsed = parse_input_day7(SAMPLE).unwrap();
+        let mut rules = BagRules::new(&parsed, Mode::Strict).unwrap();
+        let shiny_gold = BagColor::new("shiny", "gold");
+        let faded_blue = BagColor::new("faded", "blue");
+
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 32);
+        assert_eq!(rules.ancestors(&shiny_gold).unwrap().len(), 4);
+        assert_eq!(rules.ancestors(&faded_blue).unwrap().len(), 7);
+
+        // Replacing a rule inside shiny gold only invalidates its containers
+        let previous = rules
+            .insert(Bag::from_str("dark olive bags contain 1 dotted black bag.").unwrap())
+            .unwrap();
+        assert_eq!(
+            previous.unwrap().to_string(),
+            "dark olive bags contain 4 dotted black bags, 3 faded blue bags."
+        );
+        assert!(rules
+            .contents_cache
+            .borrow()
+            .contains_key(&BagColor::new("vibrant", "plum")));
+        assert!(!rules.contents_cache.borrow().contains_key(&shiny_gold));
+        assert!(!rules.ancestors_cache.borrow().contains_key(&faded_blue));
+        assert!(rules.ancestors_cache.borrow().contains_key(&shiny_gold));
+
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 26);
+        assert_eq!(rules.ancestors(&faded_blue).unwrap().len(), 6);
+
+        // New bags, and rejected edits leaving the rules untouched
+        assert_eq!(
+            rules.insert(Bag::from_str("pale pink bags contain 1 light red bag.").unwrap()),
+            Ok(None)
+        );
+        assert_eq!(rules.ancestors(&shiny_gold).unwrap().len(), 5);
+        match rules.insert(Bag::from_str("dotted black bags contain 2 pale pink bags.").unwrap()) {
+            Err(Error::Cycle(cycle)) => {
+                assert_eq!(cycle.len(), 6);
+                assert_eq!(cycle[0], BagColor::new("dotted", "black"));
+                assert_eq!(cycle[1], BagColor::new("pale", "pink"));
+                assert_eq!(cycle[4], shiny_gold);
+            }
+            other => panic!("Expected a cycle, got {:?}", other),
+        }
+        assert_eq!(
+            rules.insert(Bag::from_str("dotted black bags contain 2 pale red bags.").unwrap()),
+            Err(Error::UndefinedBag(BagColor::new("pale", "red")))
+        );
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 26);
+
+        // Removal
+        assert_eq!(
+            rules.remove(&shiny_gold),
+            Err(Error::Referenced(shiny_gold.clone()))
+        );
+        assert!(rules
+            .remove(&BagColor::new("pale", "pink"))
+            .unwrap()
+            .is_some());
+        assert_eq!(rules.remove(&BagColor::new("pale", "pink")), Ok(None));
+        assert_eq!(rules.ancestors(&shiny_gold).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn incremental_lenient() {
+        let mut rules = BagRules::new(&[], Mode::Lenient).unwrap();
+        let shiny_gold = BagColor::new("shiny", "gold");
+        let dark_red = BagColor::new("dark", "red");
+
+        rules
+            .insert(Bag::from_str("shiny gold bags contain 2 dark red bags.").unwrap())
+            .unwrap();
+        assert_eq!(rules.get(&dark_red), None);
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 2);
+
+        rules
+            .insert(Bag::from_str("dark red bags contain 3 dark blue bags.").unwrap())
+            .unwrap();
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 8);
+        assert_eq!(
+            rules
+                .ancestors(&BagColor::new("dark", "blue"))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        assert!(rules.remove(&dark_red).unwrap().is_some());
+        assert_eq!(rules.contents(&shiny_gold).unwrap().total_bags, 2);
+        assert!(rules.ancestors(&BagColor::new("dark", "blue")).is_err());
+
+        rules
+            .insert(Bag::from_str("shiny gold bags contain no other bags.").unwrap())
+            .unwrap();
+        assert!(rules.contents(&dark_red).is_err());
     }
 }
@@ -1,118 +1,513 @@
 use aoc_runner_derive::{aoc, aoc_generator};
-use std::{collections::HashMap, num::ParseIntError};
-use std::{collections::HashSet, str::FromStr};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+pub mod bytecode;
+pub mod cfg;
+pub mod isa;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Jump(isize),
     Acc(isize),
     Nop(isize),
 }
 
+impl Instruction {
+    pub fn operand(&self) -> isize {
+        match self {
+            Self::Jump(arg) | Self::Acc(arg) | Self::Nop(arg) => *arg,
+        }
+    }
+
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Self::Jump(_) => "jmp",
+            Self::Acc(_) => "acc",
+            Self::Nop(_) => "nop",
+        }
+    }
+
+    /// Parses the instruction on one line of source, returning `None` for
+    /// blank lines and comments. `line` is only used for error reporting.
+    fn parse_line(line: usize, source: &str) -> Result<Option<Self>, Error> {
+        let mut tokens = tokenize(source);
+
+        let (column, opcode) = match tokens.next() {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let build: fn(isize) -> Self = match opcode {
+            "nop" => Self::Nop,
+            "jmp" => Self::Jump,
+            "acc" => Self::Acc,
+            _ => {
+                return Err(Error::UnknownOpcode {
+                    line,
+                    column,
+                    opcode: opcode.to_owned(),
+                })
+            }
+        };
+
+        let (column, operand) = tokens.next().ok_or(Error::MissingOperand {
+            line,
+            column: column + opcode.chars().count(),
+        })?;
+        let arg = operand.parse().map_err(|_| Error::InvalidOperand {
+            line,
+            column,
+            operand: operand.to_owned(),
+        })?;
+
+        if let Some((column, token)) = tokens.next() {
+            return Err(Error::UnexpectedToken {
+                line,
+                column,
+                token: token.to_owned(),
+            });
+        }
+
+        Ok(Some(build(arg)))
+    }
+}
+
+/// Splits a line of source into tokens and their one-based columns, dropping
+/// any `;` comment.
+fn tokenize(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    let code = source.split(';').next().unwrap_or_default();
+
+    code.split_whitespace().map(move |token| {
+        let offset = token.as_ptr() as usize - code.as_ptr() as usize;
+        (code[..offset].chars().count() + 1, token)
+    })
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Jump(arg) | Self::Acc(arg) | Self::Nop(arg) => {
+                write!(f, "{} {:+}", self.opcode(), arg)
+            }
+        }
+    }
+}
+
 impl FromStr for Instruction {
-    type Err = ();
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut iter = s.trim().splitn(2, ' ');
+        Self::parse_line(1, s)?.ok_or(Error::MissingOpcode { line: 1 })
+    }
+}
 
-        let inst = iter.next().expect("No instruction!");
-        let arg = iter.last().expect("No argument!").parse().map_err(drop)?;
+/// Assembly errors, with one-based line and column numbers.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    MissingOpcode {
+        line: usize,
+    },
+    UnknownOpcode {
+        line: usize,
+        column: usize,
+        opcode: String,
+    },
+    MissingOperand {
+        line: usize,
+        column: usize,
+    },
+    InvalidOperand {
+        line: usize,
+        column: usize,
+        operand: String,
+    },
+    UnexpectedToken {
+        line: usize,
+        column: usize,
+        token: String,
+    },
+    UnsupportedVersion {
+        line: usize,
+        column: usize,
+        version: String,
+    },
+}
 
-        match inst {
-            "nop" => Ok(Self::Nop(arg)),
-            "jmp" => Ok(Self::Jump(arg)),
-            "acc" => Ok(Self::Acc(arg)),
-            _ => Err(()),
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingOpcode { line } => write!(f, "{}: Expected an instruction", line),
+            Self::UnknownOpcode {
+                line,
+                column,
+                opcode,
+            } => write!(f, "{}:{}: Unknown opcode {:?}", line, column, opcode),
+            Self::MissingOperand { line, column } => {
+                write!(f, "{}:{}: Expected an operand", line, column)
+            }
+            Self::InvalidOperand {
+                line,
+                column,
+                operand,
+            } => write!(f, "{}:{}: Invalid operand {:?}", line, column, operand),
+            Self::UnexpectedToken {
+                line,
+                column,
+                token,
+            } => write!(f, "{}:{}: Unexpected {:?}", line, column, token),
+            Self::UnsupportedVersion {
+                line,
+                column,
+                version,
+            } => write!(
+                f,
+                "{}:{}: Unsupported ISA version {:?}",
+                line, column, version
+            ),
         }
     }
 }
 
-pub struct Patcher {
-    patch_nr: usize,
+impl std::error::Error for Error {}
+
+/// A program for the handheld game console, addressed by instruction index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
 }
 
-impl Patcher {
-    pub fn new() -> Self {
-        Self { patch_nr: 0 }
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self { instructions }
     }
 
-    pub fn patch_program(
-        &mut self,
-        program: &HashMap<usize, Instruction>,
-    ) -> Option<HashMap<usize, Instruction>> {
-        let mut patched_program = program.clone();
+    /// Assembles one instruction per line. Blank lines are skipped, and `;`
+    /// starts a comment running to the end of the line.
+    pub fn assemble(source: &str) -> Result<Self, Error> {
+        let mut instructions = Vec::new();
+        for (line, text) in source.lines().enumerate() {
+            if let Some(inst) = Instruction::parse_line(line + 1, text)? {
+                instructions.push(inst);
+            }
+        }
+        Ok(Self { instructions })
+    }
 
-        loop {
-            if let Some(i) = patched_program.get_mut(&self.patch_nr) {
-                self.patch_nr += 1;
-                match i {
-                    Instruction::Nop(arg) => *i = Instruction::Jump(*arg),
-                    Instruction::Jump(arg) => *i = Instruction::Nop(*arg),
-                    _ => {
-                        continue;
-                    }
-                }
-                break;
-            } else {
-                return None;
+    /// Writes the program back as source, one instruction per line, which
+    /// assembles into the same program.
+    pub fn disassemble(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn get(&self, line: usize) -> Option<&Instruction> {
+        self.instructions.get(line)
+    }
+
+    pub fn get_mut(&mut self, line: usize) -> Option<&mut Instruction> {
+        self.instructions.get_mut(line)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for inst in self.instructions.iter() {
+            writeln!(f, "{}", inst)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Program {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::assemble(s)
+    }
+}
+
+/// Ways of changing a single instruction to repair a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairModel {
+    /// Swap a `jmp` for a `nop` or the other way around, keeping the operand.
+    SwapJumpNop,
+    /// Replace the opcode with any other opcode, keeping the operand.
+    AnyOpcode,
+    /// Negate the operand of a `jmp` or an `acc`. Negating an `acc` never
+    /// changes whether a program halts, so only jumps are ever repaired.
+    NegateOperand,
+}
+
+impl RepairModel {
+    pub fn candidates(&self, inst: &Instruction) -> Vec<Instruction> {
+        match (self, *inst) {
+            (Self::SwapJumpNop, Instruction::Jump(arg)) => vec![Instruction::Nop(arg)],
+            (Self::SwapJumpNop, Instruction::Nop(arg)) => vec![Instruction::Jump(arg)],
+            (Self::SwapJumpNop, Instruction::Acc(_)) => vec![],
+            (Self::AnyOpcode, inst) => {
+                let arg = inst.operand();
+                vec![
+                    Instruction::Jump(arg),
+                    Instruction::Acc(arg),
+                    Instruction::Nop(arg),
+                ]
+                .into_iter()
+                .filter(|candidate| *candidate != inst)
+                .collect()
+            }
+            (Self::NegateOperand, Instruction::Jump(arg)) if arg != 0 => {
+                vec![Instruction::Jump(-arg)]
+            }
+            (Self::NegateOperand, Instruction::Acc(arg)) if arg != 0 => {
+                vec![Instruction::Acc(-arg)]
+            }
+            (Self::NegateOperand, _) => vec![],
+        }
+    }
+}
+
+/// A single changed instruction that makes a program halt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repair {
+    pub line: usize,
+    pub instruction: Instruction,
+    /// Accumulator value once the repaired program halts.
+    pub accumulator: isize,
+}
+
+/// The line executed after `line`, if it is within `0..=program.len()`.
+fn successor(program: &Program, line: usize, inst: &Instruction) -> Option<usize> {
+    let offset = match inst {
+        Instruction::Jump(arg) => *arg,
+        Instruction::Acc(_) | Instruction::Nop(_) => 1,
+    };
+    let target = (line as isize).checked_add(offset)?;
+    if target < 0 || target as usize > program.len() {
+        None
+    } else {
+        Some(target as usize)
+    }
+}
+
+/// Marks every line from which the program halts, working backwards from
+/// the line just past the end of the program.
+fn halting_lines(program: &Program) -> Vec<bool> {
+    let mut predecessors = vec![Vec::new(); program.len() + 1];
+    for (line, inst) in program.instructions().iter().enumerate() {
+        if let Some(next) = successor(program, line, inst) {
+            predecessors[next].push(line);
+        }
+    }
+
+    let mut halting = vec![false; program.len() + 1];
+    let mut queue = vec![program.len()];
+    halting[program.len()] = true;
+    while let Some(line) = queue.pop() {
+        for &prev in predecessors[line].iter() {
+            if !halting[prev] {
+                halting[prev] = true;
+                queue.push(prev);
             }
         }
+    }
+
+    halting
+}
 
-        println!("Patched a new program! {:?}", self.patch_nr);
-        Some(patched_program)
+/// Finds the first instruction, along the path the program executes, that
+/// can be changed according to `model` to make the program halt.
+///
+/// Runs in time linear in the program length. Returns `None` if the program
+/// already halts, or if no single change repairs it.
+pub fn repair(program: &Program, model: RepairModel) -> Option<Repair> {
+    let halting = halting_lines(program);
+    if halting[0] {
+        return None;
+    }
+
+    let mut visited = vec![false; program.len()];
+    let mut line = 0;
+
+    let (line, instruction) = loop {
+        let inst = program.get(line)?;
+        if visited[line] {
+            return None;
+        }
+        visited[line] = true;
+
+        let fix = model.candidates(inst).into_iter().find(
+            |candidate| matches!(successor(program, line, candidate), Some(next) if halting[next]),
+        );
+        if let Some(candidate) = fix {
+            break (line, candidate);
+        }
+
+        line = successor(program, line, inst)?;
+    };
+
+    let mut patched = program.clone();
+    *patched.get_mut(line).unwrap() = instruction;
+    let mut execution = Execution::new();
+    match execution.run(&patched) {
+        Termination::Halted => Some(Repair {
+            line,
+            instruction,
+            accumulator: execution.accumulator_value(),
+        }),
+        _ => None,
     }
 }
 
+/// Why an [`Execution`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// Execution reached the line immediately after the last instruction.
+    Halted,
+    /// The instruction at `pc` was about to run a second time, with the
+    /// accumulator at `acc`.
+    LoopDetected { pc: usize, acc: isize },
+    /// A jump targeted a line outside of the program.
+    OutOfBounds { target: isize },
+    /// The step limit was reached before the program terminated.
+    StepLimitExceeded,
+    /// An `in` instruction found no value queued on `port`.
+    AwaitingInput { port: usize },
+    /// An `in` or `out` instruction referred to a port that is not connected.
+    NoSuchPort { port: isize },
+    /// A `ret` instruction at `pc` found the call stack empty.
+    StackUnderflow { pc: usize },
+    /// A `mod` instruction at `pc` divided by zero.
+    DivisionByZero { pc: usize },
+    /// An arithmetic instruction at `pc` overflowed.
+    Overflow { pc: usize },
+    /// An `acc` instruction at `pc` pushed the accumulator out of range.
+    AccumulatorOverflow { pc: usize },
+}
+
+#[derive(Default)]
 pub struct Execution {
     accumulator: isize,
     next_line: usize,
     lines_executed: HashSet<usize>,
+    steps: usize,
+    step_limit: Option<usize>,
 }
 
 impl Execution {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops execution with [`Termination::StepLimitExceeded`] after `limit`
+    /// instructions.
+    pub fn with_step_limit(limit: usize) -> Self {
         Self {
-            accumulator: 0,
-            next_line: 0,
-            lines_executed: HashSet::new(),
+            step_limit: Some(limit),
+            ..Self::default()
         }
     }
 
-    pub fn run(&mut self, program: &HashMap<usize, Instruction>) -> bool {
+    pub fn run(&mut self, program: &Program) -> Termination {
+        self.run_with(program, &mut Silent)
+    }
+
+    /// Like [`Execution::run`], reporting every step to `observer`.
+    pub fn run_with(
+        &mut self,
+        program: &Program,
+        observer: &mut impl ExecutionObserver,
+    ) -> Termination {
         loop {
-            if let Some(inst) = program.get(&self.next_line) {
-                if !self.step(inst) {
-                    return false;
-                }
-            } else {
-                return true;
+            if let Some(termination) = self.step_with(program, observer) {
+                return termination;
             }
         }
     }
 
-    pub fn step(&mut self, inst: &Instruction) -> bool {
-        println!("Stepping instruction: {:?} - {:?}", self.next_line, inst);
+    /// Executes the next instruction, returning why execution stopped if it
+    /// cannot continue.
+    pub fn step(&mut self, program: &Program) -> Option<Termination> {
+        self.step_with(program, &mut Silent)
+    }
 
-        let newline = match inst {
-            Instruction::Jump(arg) => {
-                assert!(self.next_line as isize + arg >= 0);
-                let newline: usize = (self.next_line as isize + arg) as usize;
-                if self.lines_executed.contains(&newline) {
-                    return false;
-                }
-                newline
-            }
-            Instruction::Acc(arg) => {
-                self.accumulator += arg;
-                self.next_line + 1
+    /// Like [`Execution::step`], reporting the step to `observer`.
+    pub fn step_with(
+        &mut self,
+        program: &Program,
+        observer: &mut impl ExecutionObserver,
+    ) -> Option<Termination> {
+        if self.next_line == program.len() {
+            return Some(Termination::Halted);
+        }
+
+        if matches!(self.step_limit, Some(limit) if self.steps >= limit) {
+            return Some(Termination::StepLimitExceeded);
+        }
+
+        if self.lines_executed.contains(&self.next_line) {
+            return Some(Termination::LoopDetected {
+                pc: self.next_line,
+                acc: self.accumulator,
+            });
+        }
+
+        let inst = program.get(self.next_line)?;
+        observer.before_step(self, inst);
+
+        let (offset, increment) = match inst {
+            Instruction::Jump(arg) => (*arg, 0),
+            Instruction::Acc(arg) => (1, *arg),
+            Instruction::Nop(_arg) => (1, 0),
+        };
+
+        // A target past the range of `isize` is reported saturated.
+        let target = match (self.next_line as isize).checked_add(offset) {
+            Some(target) if target >= 0 && target as usize <= program.len() => target,
+            Some(target) => return Some(Termination::OutOfBounds { target }),
+            None => {
+                return Some(Termination::OutOfBounds {
+                    target: (self.next_line as isize).saturating_add(offset),
+                })
             }
-            Instruction::Nop(_arg) => self.next_line + 1,
         };
 
-        self.lines_executed.insert(self.next_line);
-        self.next_line = newline;
+        let line = self.next_line;
+        let accumulator = match self.accumulator.checked_add(increment) {
+            Some(accumulator) => accumulator,
+            None => return Some(Termination::AccumulatorOverflow { pc: line }),
+        };
+        self.lines_executed.insert(line);
+        self.accumulator = accumulator;
+        self.next_line = target as usize;
+        self.steps += 1;
+
+        observer.after_step(self, line, inst);
+        None
+    }
+
+    /// Line of the next instruction to execute.
+    pub fn next_line(&self) -> usize {
+        self.next_line
+    }
 
-        true
+    /// Lines executed so far.
+    pub fn visited(&self) -> &HashSet<usize> {
+        &self.lines_executed
     }
 
     pub fn accumulator_value(&self) -> isize {
@@ -120,43 +515,366 @@ impl Execution {
     }
 }
 
-#[aoc_generator(day8)]
-fn parse_input_day8(input: &str) -> Result<HashMap<usize, Instruction>, ParseIntError> {
-    Ok(input
-        .lines()
-        .enumerate()
-        .map(|(l, s)| (l, s.parse().unwrap()))
-        .collect())
+/// Hooks into every step of an [`Execution`].
+pub trait ExecutionObserver {
+    /// Called before `inst`, at `execution.next_line()`, is executed.
+    fn before_step(&mut self, _execution: &Execution, _inst: &Instruction) {}
+
+    /// Called after `inst`, at `line`, has been executed.
+    fn after_step(&mut self, _execution: &Execution, _line: usize, _inst: &Instruction) {}
 }
 
-#[aoc(day8, part1)]
-pub fn part1(program: &HashMap<usize, Instruction>) -> isize {
-    let mut executor = Execution::new();
-    let success = executor.run(program);
+/// Observes nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Silent;
+
+impl ExecutionObserver for Silent {}
 
-    println!("Program terminated with success: {:?}", success);
-    executor.accumulator_value()
+/// Writes a human-readable line per executed instruction.
+pub struct Trace<W> {
+    writer: W,
+    error: Option<io::Error>,
 }
 
-#[aoc(day8, part2)]
-pub fn part2(program: &HashMap<usize, Instruction>) -> isize {
-    let mut patcher = Patcher::new();
-    loop {
-        let mut executor = Execution::new();
-        if let Some(ref patched_program) = patcher.patch_program(program) {
-            if executor.run(patched_program) {
-                println!("Program terminated with success!");
-                break executor.accumulator_value();
-            }
+impl<W: Write> Trace<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Returns the writer, or the first error encountered while tracing.
+    pub fn finish(self) -> io::Result<W> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.writer),
+        }
+    }
+}
+
+impl<W: Write> ExecutionObserver for Trace<W> {
+    fn after_step(&mut self, execution: &Execution, line: usize, inst: &Instruction) {
+        if self.error.is_none() {
+            let result = writeln!(
+                self.writer,
+                "{:>6}: {:<10} acc {:>8}  next {}",
+                line,
+                inst.to_string(),
+                execution.accumulator_value(),
+                execution.next_line()
+            );
+            self.error = result.err();
+        }
+    }
+}
+
+/// Writes a JSON object per executed instruction, one per line.
+pub struct JsonLinesTrace<W> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl JsonLinesTrace<BufWriter<File>> {
+    /// Traces to a new file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> JsonLinesTrace<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Flushes and returns the writer, or the first error encountered while
+    /// tracing.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.error {
+            Some(e) => Err(e),
+            None => self.writer.flush().map(|_| self.writer),
+        }
+    }
+}
+
+impl<W: Write> ExecutionObserver for JsonLinesTrace<W> {
+    fn after_step(&mut self, execution: &Execution, line: usize, inst: &Instruction) {
+        if self.error.is_none() {
+            let result = writeln!(
+                self.writer,
+                r#"{{"line":{},"op":"{}","arg":{},"acc":{},"next":{}}}"#,
+                line,
+                inst.opcode(),
+                inst.operand(),
+                execution.accumulator_value(),
+                execution.next_line()
+            );
+            self.error = result.err();
+        }
+    }
+}
+
+/// Counts how many times each line is executed.
+#[derive(Debug, Default, Clone)]
+pub struct Profiler {
+    hits: BTreeMap<usize, usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Executions per line, for lines executed at least once.
+    pub fn hits(&self) -> &BTreeMap<usize, usize> {
+        &self.hits
+    }
+
+    /// The `n` most executed lines, most executed first.
+    pub fn hottest(&self, n: usize) -> Vec<(usize, usize)> {
+        let mut hits: Vec<_> = self.hits.iter().map(|(l, h)| (*l, *h)).collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hits.truncate(n);
+        hits
+    }
+}
+
+impl ExecutionObserver for Profiler {
+    // Counted after the step, so an instruction that ends the run without
+    // executing is not counted.
+    fn after_step(&mut self, _execution: &Execution, line: usize, _inst: &Instruction) {
+        *self.hits.entry(line).or_default() += 1;
+    }
+}
+
+/// Where the [`Debugger`] should stop before executing an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    Line(usize),
+    /// Any instruction with this opcode, such as `"jmp"`.
+    Opcode(String),
+}
+
+/// Parses a line number, or one of the opcodes `nop`, `acc` and `jmp`.
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(line) = s.parse() {
+            return Ok(Self::Line(line));
+        }
+        let known = [
+            Instruction::Nop(0),
+            Instruction::Acc(0),
+            Instruction::Jump(0),
+        ];
+        match known.iter().find(|inst| inst.opcode() == s) {
+            Some(inst) => Ok(Self::Opcode(inst.opcode().to_owned())),
+            None => Err(format!(
+                "Unknown opcode {:?}, expected a line or nop, acc or jmp",
+                s
+            )),
+        }
+    }
+}
+
+impl Breakpoint {
+    fn hits(&self, line: usize, inst: &Instruction) -> bool {
+        match self {
+            Self::Line(l) => *l == line,
+            Self::Opcode(opcode) => opcode == inst.opcode(),
+        }
+    }
+}
+
+/// Condition on the accumulator that stops the [`Debugger`] after a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watchpoint {
+    Changed,
+    Equals(isize),
+}
+
+impl Watchpoint {
+    fn hits(&self, old: isize, new: isize) -> bool {
+        match self {
+            Self::Changed => old != new,
+            Self::Equals(value) => old != new && new == *value,
+        }
+    }
+}
+
+/// Why the [`Debugger`] stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// A single step completed without hitting anything.
+    Stepped,
+    /// About to execute `line`, which matches a breakpoint.
+    Breakpoint { line: usize },
+    /// The accumulator changed from `old` to `new`, matching a watchpoint.
+    Watchpoint { old: isize, new: isize },
+    /// The program cannot continue.
+    Terminated(Termination),
+}
+
+/// Steps through a program, keeping a history so execution can be undone.
+pub struct Debugger<'a> {
+    program: &'a Program,
+    execution: Execution,
+    /// `(line, accumulator)` before each executed step.
+    history: Vec<(usize, isize)>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            execution: Execution::new(),
+            history: Vec::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        self.program
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Removes `breakpoint`, returning whether it was set.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes `watchpoint`, returning whether it was set.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn pc(&self) -> usize {
+        self.execution.next_line()
+    }
+
+    pub fn accumulator(&self) -> isize {
+        self.execution.accumulator_value()
+    }
+
+    /// The instruction about to be executed, if any.
+    pub fn current(&self) -> Option<&Instruction> {
+        self.program.get(self.pc())
+    }
+
+    pub fn visited(&self) -> &HashSet<usize> {
+        self.execution.visited()
+    }
+
+    /// `(line, accumulator)` before each step executed so far, oldest first.
+    pub fn history(&self) -> &[(usize, isize)] {
+        &self.history
+    }
+
+    /// Executes a single instruction, ignoring breakpoints.
+    pub fn step(&mut self) -> Stop {
+        let before = (self.pc(), self.accumulator());
+
+        if let Some(termination) = self.execution.step(self.program) {
+            return Stop::Terminated(termination);
+        }
+        self.history.push(before);
+
+        let (old, new) = (before.1, self.accumulator());
+        if self.watchpoints.iter().any(|w| w.hits(old, new)) {
+            Stop::Watchpoint { old, new }
         } else {
-            panic!("Failed to find a valid patch!");
+            Stop::Stepped
+        }
+    }
+
+    /// Undoes the last executed step, returning whether there was one.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop() {
+            Some((line, accumulator)) => {
+                let execution = &mut self.execution;
+                execution.lines_executed.remove(&line);
+                execution.next_line = line;
+                execution.accumulator = accumulator;
+                execution.steps -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Executes instructions until a breakpoint or watchpoint is hit, or the
+    /// program terminates. Always executes at least one instruction, so
+    /// continuing from a breakpoint does not stop at it again immediately.
+    pub fn continue_execution(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Stepped => {}
+                stop => return stop,
+            }
+
+            let line = self.pc();
+            if let Some(inst) = self.current() {
+                if self.breakpoints.iter().any(|b| b.hits(line, inst)) {
+                    return Stop::Breakpoint { line };
+                }
+            }
         }
     }
 }
 
+#[aoc_generator(day8)]
+fn parse_input_day8(input: &str) -> Result<Program, Error> {
+    input.parse()
+}
+
+#[aoc(day8, part1)]
+pub fn part1(program: &Program) -> Option<isize> {
+    match Execution::new().run(program) {
+        Termination::LoopDetected { acc, .. } => Some(acc),
+        _ => None,
+    }
+}
+
+#[aoc(day8, part2)]
+pub fn part2(program: &Program) -> Option<isize> {
+    repair(program, RepairModel::SwapJumpNop).map(|repair| repair.accumulator)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeSet;
+    use std::fs;
 
     const SAMPLE: &str = "nop +0
     acc +1
@@ -171,12 +889,320 @@ mod tests {
     #[test]
     fn sample1() {
         let parsed = parse_input_day8(SAMPLE).unwrap();
-        assert_eq!(part1(&parsed), 5);
+        assert_eq!(part1(&parsed), Some(5));
     }
 
     #[test]
     fn sample2() {
         let parsed = parse_input_day8(SAMPLE).unwrap();
-        assert_eq!(part2(&parsed), 8);
+        assert_eq!(part2(&parsed), Some(8));
+    }
+
+    #[test]
+    fn assemble_comments_and_blank_lines() {
+        let program = Program::assemble(
+            "; boot sequence
+            nop +0  ; nothing
+
+            acc -12
+            jmp 3",
+        )
+        .unwrap();
+        assert_eq!(
+            program.instructions(),
+            &[
+                Instruction::Nop(0),
+                Instruction::Acc(-12),
+                Instruction::Jump(3)
+            ]
+        );
+        assert_eq!(program.disassemble(), "nop +0\nacc -12\njmp +3\n");
+    }
+
+    #[test]
+    fn assemble_errors() {
+        assert_eq!(
+            Program::assemble("nop +0\n  jpm +4"),
+            Err(Error::UnknownOpcode {
+                line: 2,
+                column: 3,
+                opcode: "jpm".to_owned()
+            })
+        );
+        assert_eq!(
+            Program::assemble("acc +1\n\nacc  4x"),
+            Err(Error::InvalidOperand {
+                line: 3,
+                column: 6,
+                operand: "4x".to_owned()
+            })
+        );
+        assert_eq!(
+            Program::assemble("acc ; +1"),
+            Err(Error::MissingOperand { line: 1, column: 4 })
+        );
+        assert_eq!(
+            Program::assemble("acc +1 +2"),
+            Err(Error::UnexpectedToken {
+                line: 1,
+                column: 8,
+                token: "+2".to_owned()
+            })
+        );
+        assert_eq!(
+            Instruction::from_str("  "),
+            Err(Error::MissingOpcode { line: 1 })
+        );
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let program = parse_input_day8(include_str!("../input/2020/day8.txt")).unwrap();
+        assert_eq!(Program::assemble(&program.disassemble()), Ok(program));
+    }
+
+    #[test]
+    fn terminations() {
+        let run = |source: &str| Execution::new().run(&Program::assemble(source).unwrap());
+
+        assert_eq!(run("acc +1\nnop +0"), Termination::Halted);
+        assert_eq!(run("acc +1\njmp +2\nnop +0"), Termination::Halted);
+        assert_eq!(
+            run("acc +1\njmp +3\nnop +0"),
+            Termination::OutOfBounds { target: 4 }
+        );
+        assert_eq!(
+            run("acc +1\njmp -1"),
+            Termination::LoopDetected { pc: 0, acc: 1 }
+        );
+        assert_eq!(
+            run("acc +2\njmp -3"),
+            Termination::OutOfBounds { target: -2 }
+        );
+        assert_eq!(run("jmp +0"), Termination::LoopDetected { pc: 0, acc: 0 });
+        assert_eq!(run(""), Termination::Halted);
+
+        let max = format!("acc +{}\nacc +1", isize::MAX);
+        assert_eq!(run(&max), Termination::AccumulatorOverflow { pc: 1 });
+        let far = format!("nop +0\njmp +{}", isize::MAX);
+        assert_eq!(run(&far), Termination::OutOfBounds { target: isize::MAX });
+        let min = format!("acc {}\nacc -1\nnop +0", isize::MIN);
+        assert_eq!(run(&min), Termination::AccumulatorOverflow { pc: 1 });
+    }
+
+    #[test]
+    fn fall_through_loop() {
+        // Jumping back, then falling through into a line already executed,
+        // must be detected as a loop too.
+        let program = Program::assemble("jmp +2\nacc +5\nacc +1\njmp -2").unwrap();
+        assert_eq!(
+            Execution::new().run(&program),
+            Termination::LoopDetected { pc: 2, acc: 6 }
+        );
+    }
+
+    #[test]
+    fn step_limit() {
+        let program = parse_input_day8(SAMPLE).unwrap();
+        assert_eq!(
+            Execution::with_step_limit(3).run(&program),
+            Termination::StepLimitExceeded
+        );
+        assert_eq!(
+            Execution::with_step_limit(100).run(&program),
+            Termination::LoopDetected { pc: 1, acc: 5 }
+        );
+    }
+
+    #[test]
+    fn repair_models() {
+        let parsed = parse_input_day8(SAMPLE).unwrap();
+        assert_eq!(
+            repair(&parsed, RepairModel::SwapJumpNop),
+            Some(Repair {
+                line: 7,
+                instruction: Instruction::Nop(-4),
+                accumulator: 8
+            })
+        );
+        assert_eq!(
+            repair(&parsed, RepairModel::AnyOpcode),
+            Some(Repair {
+                line: 7,
+                instruction: Instruction::Acc(-4),
+                accumulator: 4
+            })
+        );
+
+        let program = Program::assemble("acc +1\njmp -1\nacc +2").unwrap();
+        assert_eq!(
+            repair(&program, RepairModel::NegateOperand),
+            Some(Repair {
+                line: 1,
+                instruction: Instruction::Jump(1),
+                accumulator: 3
+            })
+        );
+        assert_eq!(
+            repair(&program, RepairModel::SwapJumpNop)
+                .unwrap()
+                .accumulator,
+            3
+        );
+
+        let halting = Program::assemble("acc +1").unwrap();
+        assert_eq!(repair(&halting, RepairModel::AnyOpcode), None);
+        let unrepairable = Program::assemble("jmp +0\njmp -1").unwrap();
+        assert_eq!(repair(&unrepairable, RepairModel::SwapJumpNop), None);
+
+        let far = Program::assemble(&format!("nop +0\njmp +{}", isize::MAX)).unwrap();
+        assert_eq!(
+            repair(&far, RepairModel::SwapJumpNop),
+            Some(Repair {
+                line: 1,
+                instruction: Instruction::Nop(isize::MAX),
+                accumulator: 0
+            })
+        );
+    }
+
+    #[test]
+    fn repair_large_program() {
+        // A chain of jumps hopping over a loop that needs fixing at the end
+        let mut instructions = Vec::new();
+        for _ in 0..20_000 {
+            instructions.push(Instruction::Jump(2));
+            instructions.push(Instruction::Acc(1));
+        }
+        instructions.push(Instruction::Jump(-1));
+        instructions.push(Instruction::Acc(7));
+        let program = Program::new(instructions);
+
+        assert_eq!(
+            repair(&program, RepairModel::SwapJumpNop),
+            Some(Repair {
+                line: 40_000,
+                instruction: Instruction::Nop(-1),
+                accumulator: 7
+            })
+        );
+    }
+
+    #[test]
+    fn debugger() {
+        let program = parse_input_day8(SAMPLE).unwrap();
+        let mut debugger = Debugger::new(&program);
+
+        assert_eq!("jmp".parse(), Ok(Breakpoint::Opcode("jmp".to_owned())));
+        assert_eq!("4".parse(), Ok(Breakpoint::Line(4)));
+        assert!("jpm".parse::<Breakpoint>().is_err());
+        assert!("-1".parse::<Breakpoint>().is_err());
+
+        debugger.add_breakpoint(Breakpoint::Opcode("jmp".to_owned()));
+        assert_eq!(debugger.continue_execution(), Stop::Breakpoint { line: 2 });
+        assert_eq!(debugger.accumulator(), 1);
+
+        debugger.add_breakpoint(Breakpoint::Line(4));
+        assert!(debugger.remove_breakpoint(&Breakpoint::Opcode("jmp".to_owned())));
+        assert_eq!(debugger.continue_execution(), Stop::Breakpoint { line: 4 });
+        assert_eq!(debugger.accumulator(), 5);
+        assert_eq!(
+            debugger.visited().iter().copied().collect::<BTreeSet<_>>(),
+            vec![0, 1, 2, 3, 6, 7].into_iter().collect()
+        );
+
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert_eq!(debugger.pc(), 7);
+        assert_eq!(debugger.accumulator(), 2);
+        assert!(!debugger.visited().contains(&7));
+
+        debugger.add_watchpoint(Watchpoint::Changed);
+        assert_eq!(debugger.step(), Stop::Stepped);
+        assert_eq!(debugger.step(), Stop::Watchpoint { old: 2, new: 5 });
+        assert_eq!(debugger.pc(), 4);
+        assert_eq!(
+            debugger.continue_execution(),
+            Stop::Terminated(Termination::LoopDetected { pc: 1, acc: 5 })
+        );
+
+        while debugger.step_back() {}
+        assert_eq!((debugger.pc(), debugger.accumulator()), (0, 0));
+        assert!(debugger.visited().is_empty());
+    }
+
+    #[test]
+    fn observers() {
+        let program = Program::assemble("acc +3\njmp +2\nacc -99\nnop +0").unwrap();
+
+        let mut trace = Trace::new(Vec::new());
+        assert_eq!(
+            Execution::new().run_with(&program, &mut trace),
+            Termination::Halted
+        );
+        assert_eq!(
+            String::from_utf8(trace.finish().unwrap()).unwrap(),
+            "     0: acc +3     acc        3  next 1
+     1: jmp +2     acc        3  next 3
+     3: nop +0     acc        3  next 4
+"
+        );
+
+        let path = std::env::temp_dir().join(format!("day8-trace-{}.jsonl", std::process::id()));
+        let mut trace = JsonLinesTrace::create(&path).unwrap();
+        Execution::new().run_with(&program, &mut trace);
+        trace.finish().unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            json.lines().next(),
+            Some(r#"{"line":0,"op":"acc","arg":3,"acc":3,"next":1}"#)
+        );
+        assert_eq!(json.lines().count(), 3);
+    }
+
+    #[test]
+    fn profiler() {
+        let program = parse_input_day8(SAMPLE).unwrap();
+        let mut profiler = Profiler::new();
+        Execution::new().run_with(&program, &mut profiler);
+
+        assert_eq!(profiler.hits().len(), 7);
+        assert!(profiler.hits().values().all(|&hits| hits == 1));
+        assert_eq!(profiler.hottest(2), vec![(0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn profiler_skips_terminating_steps() {
+        let profile = |source: &str, execution: &mut Execution| {
+            let mut profiler = Profiler::new();
+            let termination =
+                execution.run_with(&Program::assemble(source).unwrap(), &mut profiler);
+            (
+                termination,
+                profiler.hits().clone().into_iter().collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            profile("acc +1\njmp -1", &mut Execution::new()),
+            (
+                Termination::LoopDetected { pc: 0, acc: 1 },
+                vec![(0, 1), (1, 1)]
+            )
+        );
+        assert_eq!(
+            profile("acc +1\njmp +5", &mut Execution::new()),
+            (Termination::OutOfBounds { target: 6 }, vec![(0, 1)])
+        );
+        assert_eq!(
+            profile("nop +0\nnop +0\nnop +0", &mut Execution::with_step_limit(2)),
+            (Termination::StepLimitExceeded, vec![(0, 1), (1, 1)])
+        );
+        let overflow = format!("acc +{}\nacc +1", isize::MAX);
+        assert_eq!(
+            profile(&overflow, &mut Execution::new()),
+            (Termination::AccumulatorOverflow { pc: 1 }, vec![(0, 1)])
+        );
     }
 }
@@ -0,0 +1,357 @@
+//! Binary encoding of [`Program`]s.
+//!
+//! An image is laid out as:
+//!
+//! | bytes    | contents                                                  |
+//! |----------|-----------------------------------------------------------|
+//! | 4        | magic, `b"AOC8"`                                          |
+//! | 1        | format version, currently `1`                             |
+//! | varint   | number of instructions                                    |
+//! | ...      | per instruction, an opcode byte and a zigzag varint       |
+//! | 4        | CRC-32 of all preceding bytes, little-endian              |
+//!
+//! Varints are unsigned LEB128. Operands are zigzag encoded first, so small
+//! negative offsets take a single byte like small positive ones.
+
+use std::fmt::{self, Display};
+
+use super::{Instruction, Program};
+
+pub const MAGIC: &[u8; 4] = b"AOC8";
+pub const VERSION: u8 = 1;
+
+const OP_NOP: u8 = 0;
+const OP_ACC: u8 = 1;
+const OP_JMP: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The image does not start with [`MAGIC`].
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The image ended before the byte at `offset`.
+    Truncated {
+        offset: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    UnknownOpcode {
+        offset: usize,
+        opcode: u8,
+    },
+    /// The varint at `offset` does not fit in 64 bits.
+    VarintOverflow {
+        offset: usize,
+    },
+    /// The operand at `offset` does not fit in an `isize`.
+    OperandOverflow {
+        offset: usize,
+    },
+    /// Bytes follow the last instruction, starting at `offset`.
+    TrailingBytes {
+        offset: usize,
+    },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "Not a program image"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported image version {}", version)
+            }
+            Self::Truncated { offset } => write!(f, "Image truncated at byte {}", offset),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:08x}, found {:08x}",
+                expected, actual
+            ),
+            Self::UnknownOpcode { offset, opcode } => {
+                write!(f, "Unknown opcode {:#04x} at byte {}", opcode, offset)
+            }
+            Self::VarintOverflow { offset } => {
+                write!(f, "Varint at byte {} is too long", offset)
+            }
+            Self::OperandOverflow { offset } => {
+                write!(f, "Operand at byte {} is out of range", offset)
+            }
+            Self::TrailingBytes { offset } => {
+                write!(
+                    f,
+                    "Unexpected bytes after the last instruction at byte {}",
+                    offset
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: isize) -> u64 {
+    let value = value as i64;
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.offset).ok_or(DecodeError::Truncated {
+            offset: self.offset,
+        })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let start = self.offset;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if bits << shift >> shift != bits {
+                return Err(DecodeError::VarintOverflow { offset: start });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::VarintOverflow { offset: start })
+    }
+}
+
+impl Program {
+    /// Encodes the program as a binary image.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_varint(&mut out, self.len() as u64);
+        for inst in self.instructions() {
+            out.push(match inst {
+                Instruction::Nop(_) => OP_NOP,
+                Instruction::Acc(_) => OP_ACC,
+                Instruction::Jump(_) => OP_JMP,
+            });
+            write_varint(&mut out, zigzag(inst.operand()));
+        }
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Decodes a binary image written by [`Program::to_bytes`].
+    ///
+    /// The checksum is verified before anything else is decoded, so a
+    /// corrupted image is reported as a [`DecodeError::ChecksumMismatch`]
+    /// rather than as whatever the corruption happens to look like.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if bytes.len() < MAGIC.len() + 1 + 4 {
+            return Err(DecodeError::Truncated {
+                offset: bytes.len(),
+            });
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut expected = [0; 4];
+        expected.copy_from_slice(checksum);
+        let expected = u32::from_le_bytes(expected);
+        let actual = crc32(body);
+        if expected != actual {
+            return Err(DecodeError::ChecksumMismatch { expected, actual });
+        }
+
+        let mut reader = Reader {
+            bytes: body,
+            offset: MAGIC.len(),
+        };
+        let version = reader.byte()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let count = reader.varint()?;
+        // Each instruction takes at least two bytes, which bounds the
+        // allocation for a corrupt count that still passed the checksum.
+        let mut instructions = Vec::with_capacity((count as usize).min(body.len() / 2));
+        for _ in 0..count {
+            let offset = reader.offset;
+            let opcode = reader.byte()?;
+            let operand_offset = reader.offset;
+            let operand = unzigzag(reader.varint()?);
+            let operand = if operand >= isize::MIN as i64 && operand <= isize::MAX as i64 {
+                operand as isize
+            } else {
+                return Err(DecodeError::OperandOverflow {
+                    offset: operand_offset,
+                });
+            };
+            instructions.push(match opcode {
+                OP_NOP => Instruction::Nop(operand),
+                OP_ACC => Instruction::Acc(operand),
+                OP_JMP => Instruction::Jump(operand),
+                opcode => return Err(DecodeError::UnknownOpcode { offset, opcode }),
+            });
+        }
+
+        if reader.offset != body.len() {
+            return Err(DecodeError::TrailingBytes {
+                offset: reader.offset,
+            });
+        }
+
+        Ok(Self::new(instructions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "nop +0
+    acc +1
+    jmp +4
+    acc +3
+    jmp -3
+    acc -99
+    acc +1
+    jmp -4
+    acc +6";
+
+    fn seal(mut body: Vec<u8>) -> Vec<u8> {
+        let checksum = crc32(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn varints() {
+        for &value in [0, 1, -1, 63, -64, 64, 1000, isize::MAX, isize::MIN].iter() {
+            let mut out = Vec::new();
+            write_varint(&mut out, zigzag(value));
+            let mut reader = Reader {
+                bytes: &out,
+                offset: 0,
+            };
+            assert_eq!(unzigzag(reader.varint().unwrap()), value as i64);
+            assert_eq!(reader.offset, out.len());
+        }
+
+        let mut out = Vec::new();
+        write_varint(&mut out, zigzag(-64));
+        assert_eq!(out, vec![0x7f]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = Program::assemble(SAMPLE).unwrap();
+        let bytes = program.to_bytes();
+
+        assert_eq!(&bytes[..5], b"AOC8\x01");
+        assert_eq!(bytes[5], 9);
+        assert_eq!(&bytes[6..8], &[OP_NOP, 0]);
+        assert_eq!(&bytes[16..19], &[OP_ACC, 0xc5, 0x01]);
+        // Header, 9 two-byte instructions, one extra operand byte for -99,
+        // and the checksum.
+        assert_eq!(bytes.len(), 6 + 18 + 1 + 4);
+
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+        assert_eq!(
+            Program::from_bytes(&Program::default().to_bytes()),
+            Ok(Program::default())
+        );
+    }
+
+    #[test]
+    fn corruption() {
+        let bytes = Program::assemble(SAMPLE).unwrap().to_bytes();
+
+        for i in 4..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert!(
+                matches!(
+                    Program::from_bytes(&corrupt),
+                    Err(DecodeError::ChecksumMismatch { .. })
+                ),
+                "flipped byte {}",
+                i
+            );
+        }
+        assert!(matches!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(Program::from_bytes(b"AOC7"), Err(DecodeError::BadMagic));
+        assert_eq!(
+            Program::from_bytes(b"AOC8\x01"),
+            Err(DecodeError::Truncated { offset: 5 })
+        );
+    }
+
+    #[test]
+    fn malformed_images() {
+        assert_eq!(
+            Program::from_bytes(&seal(b"AOC8\x02\x00".to_vec())),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            Program::from_bytes(&seal(b"AOC8\x01\x01\x07\x00".to_vec())),
+            Err(DecodeError::UnknownOpcode {
+                offset: 6,
+                opcode: 7
+            })
+        );
+        assert_eq!(
+            Program::from_bytes(&seal(b"AOC8\x01\x02\x00\x00".to_vec())),
+            Err(DecodeError::Truncated { offset: 8 })
+        );
+        assert_eq!(
+            Program::from_bytes(&seal(b"AOC8\x01\x01\x00\x00\x00".to_vec())),
+            Err(DecodeError::TrailingBytes { offset: 8 })
+        );
+
+        let mut body = b"AOC8\x01\x01\x00".to_vec();
+        body.extend_from_slice(&[0xff; 10]);
+        body.push(0x01);
+        assert_eq!(
+            Program::from_bytes(&seal(body)),
+            Err(DecodeError::VarintOverflow { offset: 7 })
+        );
+    }
+}
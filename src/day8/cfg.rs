@@ -0,0 +1,552 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use super::{Instruction, Program, RepairModel};
+
+/// Where control goes after a line or a basic block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Target {
+    Line(usize),
+    /// The line just past the last instruction, where the program halts.
+    Exit,
+    OutOfBounds(isize),
+}
+
+/// How execution starting at a given line ends, determined without running
+/// the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Halts,
+    Loops,
+    OutOfBounds,
+}
+
+/// A maximal run of instructions only ever entered at its first line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// One past the last line of the block.
+    pub end: usize,
+    pub successor: Target,
+}
+
+impl BasicBlock {
+    pub fn lines(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// An instruction that, swapped between `jmp` and `nop`, changes how the
+/// program terminates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flip {
+    pub line: usize,
+    pub instruction: Instruction,
+    pub outcome: Outcome,
+}
+
+/// Control flow graph of a [`Program`].
+///
+/// Every instruction has exactly one successor, so the graph from each line
+/// is a path ending at the exit, out of bounds, or in a cycle. All the
+/// analyses are linear in the program length.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph<'a> {
+    program: &'a Program,
+    blocks: Vec<BasicBlock>,
+    /// Index into `blocks` of the block containing each line.
+    block_of: Vec<usize>,
+    outcomes: Vec<Outcome>,
+    /// Index into `cycles` of the cycle each line is part of.
+    cycle_of: Vec<Option<usize>>,
+    /// Index into `cycles` of the cycle execution from each line ends in.
+    sink: Vec<Option<usize>>,
+    cycles: Vec<Vec<usize>>,
+    /// Pre-order entry and exit times in the forest of predecessors, rooted
+    /// at lines leaving the program and at lines on cycles.
+    entry: Vec<usize>,
+    exit: Vec<usize>,
+    /// Lines executed from line 0, in order.
+    path: Vec<usize>,
+    reachable: Vec<bool>,
+}
+
+fn target(line: usize, inst: &Instruction, len: usize) -> Target {
+    let offset = match inst {
+        Instruction::Jump(arg) => *arg,
+        Instruction::Acc(_) | Instruction::Nop(_) => 1,
+    };
+    // A target past the range of `isize` is reported saturated.
+    let next = match (line as isize).checked_add(offset) {
+        Some(next) => next,
+        None => return Target::OutOfBounds((line as isize).saturating_add(offset)),
+    };
+    match next {
+        n if n < 0 || n as usize > len => Target::OutOfBounds(n),
+        n if n as usize == len => Target::Exit,
+        n => Target::Line(n as usize),
+    }
+}
+
+impl<'a> ControlFlowGraph<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        let len = program.len();
+        let next: Vec<_> = program
+            .instructions()
+            .iter()
+            .enumerate()
+            .map(|(line, inst)| target(line, inst, len))
+            .collect();
+
+        let mut graph = Self {
+            program,
+            blocks: Vec::new(),
+            block_of: vec![0; len],
+            outcomes: vec![Outcome::Halts; len],
+            cycle_of: vec![None; len],
+            sink: vec![None; len],
+            cycles: Vec::new(),
+            entry: vec![0; len],
+            exit: vec![0; len],
+            path: Vec::new(),
+            reachable: vec![false; len],
+        };
+        graph.find_blocks(&next);
+        graph.find_outcomes(&next);
+        graph.number_predecessors(&next);
+
+        let mut line = 0;
+        while line < len && !graph.reachable[line] {
+            graph.reachable[line] = true;
+            graph.path.push(line);
+            match next[line] {
+                Target::Line(n) => line = n,
+                _ => break,
+            }
+        }
+
+        graph
+    }
+
+    fn find_blocks(&mut self, next: &[Target]) {
+        let len = self.program.len();
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for (line, inst) in self.program.instructions().iter().enumerate() {
+            if let Instruction::Jump(_) = inst {
+                if let Target::Line(n) = next[line] {
+                    leaders.insert(n);
+                }
+                if line + 1 < len {
+                    leaders.insert(line + 1);
+                }
+            }
+        }
+
+        let starts: Vec<_> = leaders.into_iter().collect();
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(len);
+            for line in start..end {
+                self.block_of[line] = i;
+            }
+            self.blocks.push(BasicBlock {
+                start,
+                end,
+                successor: next[end - 1],
+            });
+        }
+    }
+
+    /// Follows each line's path until it reaches a line already resolved,
+    /// leaves the program, or closes a cycle.
+    fn find_outcomes(&mut self, next: &[Target]) {
+        const UNSEEN: u8 = 0;
+        const ON_PATH: u8 = 1;
+        const DONE: u8 = 2;
+
+        let mut state = vec![UNSEEN; next.len()];
+        for start in 0..next.len() {
+            let mut path = Vec::new();
+            let mut line = start;
+            let (outcome, sink) = loop {
+                match state[line] {
+                    DONE => break (self.outcomes[line], self.sink[line]),
+                    ON_PATH => {
+                        let from = path.iter().position(|&l| l == line).unwrap();
+                        let cycle = path.split_off(from);
+                        let id = self.cycles.len();
+                        for &l in cycle.iter() {
+                            self.cycle_of[l] = Some(id);
+                            self.outcomes[l] = Outcome::Loops;
+                            self.sink[l] = Some(id);
+                            state[l] = DONE;
+                        }
+                        self.cycles.push(cycle);
+                        break (Outcome::Loops, Some(id));
+                    }
+                    _ => {}
+                }
+
+                state[line] = ON_PATH;
+                path.push(line);
+                match next[line] {
+                    Target::Line(n) => line = n,
+                    Target::Exit => break (Outcome::Halts, None),
+                    Target::OutOfBounds(_) => break (Outcome::OutOfBounds, None),
+                }
+            };
+
+            for l in path {
+                self.outcomes[l] = outcome;
+                self.sink[l] = sink;
+                state[l] = DONE;
+            }
+        }
+
+        for cycle in self.cycles.iter_mut() {
+            let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+            cycle.rotate_left(first);
+        }
+    }
+
+    fn number_predecessors(&mut self, next: &[Target]) {
+        let len = next.len();
+        let mut children = vec![Vec::new(); len];
+        let mut roots = Vec::new();
+        for (line, target) in next.iter().enumerate() {
+            match *target {
+                Target::Line(n) if self.cycle_of[line].is_none() => children[n].push(line),
+                _ => roots.push(line),
+            }
+        }
+
+        let mut time = 0;
+        for root in roots {
+            let mut stack = vec![(root, 0)];
+            self.entry[root] = time;
+            time += 1;
+            while let Some((line, child)) = stack.pop() {
+                match children[line].get(child) {
+                    Some(&c) => {
+                        stack.push((line, child + 1));
+                        self.entry[c] = time;
+                        time += 1;
+                        stack.push((c, 0));
+                    }
+                    None => self.exit[line] = time,
+                }
+            }
+        }
+    }
+
+    /// Whether execution from `from` passes through `line`.
+    fn passes_through(&self, from: usize, line: usize) -> bool {
+        match self.cycle_of[line] {
+            Some(cycle) => self.sink[from] == Some(cycle),
+            None => self.entry[line] <= self.entry[from] && self.entry[from] < self.exit[line],
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        self.program
+    }
+
+    /// Basic blocks in line order.
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    pub fn block_containing(&self, line: usize) -> Option<&BasicBlock> {
+        self.block_of.get(line).map(|&i| &self.blocks[i])
+    }
+
+    /// How execution starting at `line` ends.
+    pub fn outcome(&self, line: usize) -> Option<Outcome> {
+        self.outcomes.get(line).copied()
+    }
+
+    /// How the program ends when run from line 0.
+    pub fn termination(&self) -> Outcome {
+        self.outcome(0).unwrap_or(Outcome::Halts)
+    }
+
+    pub fn is_reachable(&self, line: usize) -> bool {
+        self.reachable.get(line).copied().unwrap_or(false)
+    }
+
+    /// Lines never executed when running from line 0.
+    pub fn unreachable(&self) -> Vec<usize> {
+        (0..self.program.len())
+            .filter(|&l| !self.reachable[l])
+            .collect()
+    }
+
+    /// Every cycle in the program, in execution order starting from its
+    /// lowest line. Execution entering a cycle never terminates.
+    pub fn loops(&self) -> &[Vec<usize>] {
+        &self.cycles
+    }
+
+    /// Instructions executed from line 0 which, swapped between `jmp` and
+    /// `nop`, change how the program terminates.
+    pub fn critical_flips(&self) -> Vec<Flip> {
+        let len = self.program.len();
+        let termination = self.termination();
+
+        self.path
+            .iter()
+            .flat_map(|&line| {
+                let inst = &self.program.instructions()[line];
+                RepairModel::SwapJumpNop
+                    .candidates(inst)
+                    .into_iter()
+                    .map(move |flipped| (line, flipped))
+            })
+            .filter_map(|(line, instruction)| {
+                let outcome = match target(line, &instruction, len) {
+                    Target::Exit => Outcome::Halts,
+                    Target::OutOfBounds(_) => Outcome::OutOfBounds,
+                    Target::Line(n) if self.passes_through(n, line) => Outcome::Loops,
+                    Target::Line(n) => self.outcomes[n],
+                };
+                Some(Flip {
+                    line,
+                    instruction,
+                    outcome,
+                })
+                .filter(|_| outcome != termination)
+            })
+            .collect()
+    }
+
+    /// Writes the graph in Graphviz DOT format, one node per basic block.
+    /// Unreachable blocks are dashed and blocks on a cycle are red.
+    pub fn write_dot(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let name = |target: Target| match target {
+            Target::Line(line) => format!("b{}", self.blocks[self.block_of[line]].start),
+            Target::Exit => "exit".to_owned(),
+            Target::OutOfBounds(_) => "out_of_bounds".to_owned(),
+        };
+
+        writeln!(w, "digraph program {{")?;
+        writeln!(w, "    node [shape=box, fontname=monospace];")?;
+        for block in self.blocks.iter() {
+            write!(w, "    b{} [label=\"", block.start)?;
+            for line in block.lines() {
+                write!(w, "{}: {}\\l", line, self.program.instructions()[line])?;
+            }
+            write!(w, "\"")?;
+            if !self.is_reachable(block.start) {
+                write!(w, ", style=dashed, color=gray")?;
+            } else if self.cycle_of[block.start].is_some() {
+                write!(w, ", color=red")?;
+            }
+            writeln!(w, "];")?;
+        }
+        writeln!(w, "    exit [shape=doublecircle];")?;
+        if self
+            .blocks
+            .iter()
+            .any(|b| matches!(b.successor, Target::OutOfBounds(_)))
+        {
+            writeln!(w, "    out_of_bounds [shape=octagon];")?;
+        }
+        for block in self.blocks.iter() {
+            write!(w, "    b{} -> {}", block.start, name(block.successor))?;
+            if let Target::OutOfBounds(target) = block.successor {
+                write!(w, " [label=\"{}\"]", target)?;
+            }
+            writeln!(w, ";")?;
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes a summary of unreachable lines, cycles and critical flips.
+    pub fn write_report(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let join = |lines: &[usize], sep: &str| {
+            lines
+                .iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>()
+                .join(sep)
+        };
+
+        writeln!(w, "terminates: {:?}", self.termination())?;
+        writeln!(w, "unreachable: {}", join(&self.unreachable(), ", "))?;
+        for cycle in self.cycles.iter() {
+            writeln!(w, "loop: {}", join(cycle, " -> "))?;
+        }
+        for flip in self.critical_flips() {
+            writeln!(
+                w,
+                "flip: {} {} -> {} ({:?})",
+                flip.line,
+                self.program.instructions()[flip.line],
+                flip.instruction,
+                flip.outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::day8::{Execution, Termination};
+
+    const SAMPLE: &str = "nop +0
+    acc +1
+    jmp +4
+    acc +3
+    jmp -3
+    acc -99
+    acc +1
+    jmp -4
+    acc +6";
+
+    #[test]
+    fn sample_structure() {
+        let program = Program::assemble(SAMPLE).unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        let blocks: Vec<_> = cfg
+            .blocks()
+            .iter()
+            .map(|b| (b.start, b.end, b.successor))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0, 1, Target::Line(1)),
+                (1, 3, Target::Line(6)),
+                (3, 5, Target::Line(1)),
+                (5, 6, Target::Line(6)),
+                (6, 8, Target::Line(3)),
+                (8, 9, Target::Exit),
+            ]
+        );
+        assert_eq!(cfg.block_containing(7).unwrap().start, 6);
+
+        assert_eq!(cfg.termination(), Outcome::Loops);
+        assert_eq!(cfg.outcome(5), Some(Outcome::Loops));
+        assert_eq!(cfg.outcome(8), Some(Outcome::Halts));
+        assert_eq!(cfg.unreachable(), vec![5, 8]);
+        assert_eq!(cfg.loops(), &[vec![1, 2, 6, 7, 3, 4]]);
+        assert_eq!(
+            cfg.critical_flips(),
+            vec![Flip {
+                line: 7,
+                instruction: Instruction::Nop(-4),
+                outcome: Outcome::Halts
+            }]
+        );
+    }
+
+    #[test]
+    fn flips_that_break_a_halting_program() {
+        let program = Program::assemble("nop +0\njmp +2\nacc +1\nnop -2").unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        assert_eq!(cfg.termination(), Outcome::Halts);
+        assert!(cfg.loops().is_empty());
+        let lines: Vec<_> = cfg
+            .critical_flips()
+            .iter()
+            .map(|f| (f.line, f.outcome))
+            .collect();
+        assert_eq!(lines, vec![(0, Outcome::Loops), (3, Outcome::Loops)]);
+
+        let program = Program::assemble("nop +3\njmp +5").unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+        assert_eq!(cfg.termination(), Outcome::OutOfBounds);
+        let lines: Vec<_> = cfg
+            .critical_flips()
+            .iter()
+            .map(|f| (f.line, f.outcome))
+            .collect();
+        assert_eq!(lines, vec![(1, Outcome::Halts)]);
+
+        let program = Program::assemble(&format!("nop +0\njmp +{}", isize::MAX)).unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+        assert_eq!(cfg.termination(), Outcome::OutOfBounds);
+        assert_eq!(
+            cfg.blocks().last().map(|b| b.successor),
+            Some(Target::OutOfBounds(isize::MAX))
+        );
+        let lines: Vec<_> = cfg
+            .critical_flips()
+            .iter()
+            .map(|f| (f.line, f.outcome))
+            .collect();
+        assert_eq!(lines, vec![(0, Outcome::Loops), (1, Outcome::Halts)]);
+    }
+
+    #[test]
+    fn flips_agree_with_execution() {
+        let program = Program::assemble(
+            "jmp +2\nacc +1\nnop +3\njmp -1\njmp -3\nnop -5\njmp +2\njmp -7\nacc +2",
+        )
+        .unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+        let flips = cfg.critical_flips();
+
+        for &line in cfg.path.iter() {
+            for flipped in RepairModel::SwapJumpNop.candidates(&program.instructions()[line]) {
+                let mut patched = program.clone();
+                *patched.get_mut(line).unwrap() = flipped;
+                let outcome = match Execution::new().run(&patched) {
+                    Termination::Halted => Outcome::Halts,
+                    Termination::OutOfBounds { .. } => Outcome::OutOfBounds,
+                    _ => Outcome::Loops,
+                };
+                let flip = flips.iter().find(|f| f.line == line);
+                assert_eq!(
+                    flip.map(|f| f.outcome),
+                    Some(outcome).filter(|&o| o != cfg.termination()),
+                    "flip at line {}",
+                    line
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dot_and_report() {
+        let program = Program::assemble("nop +0\njmp +2\nacc +1\njmp -3\njmp +9").unwrap();
+        let cfg = ControlFlowGraph::new(&program);
+
+        let mut dot = Vec::new();
+        cfg.write_dot(&mut dot).unwrap();
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph program {
+    node [shape=box, fontname=monospace];
+    b0 [label=\"0: nop +0\\l1: jmp +2\\l\", color=red];
+    b2 [label=\"2: acc +1\\l\", style=dashed, color=gray];
+    b3 [label=\"3: jmp -3\\l\", color=red];
+    b4 [label=\"4: jmp +9\\l\", style=dashed, color=gray];
+    exit [shape=doublecircle];
+    out_of_bounds [shape=octagon];
+    b0 -> b3;
+    b2 -> b3;
+    b3 -> b0;
+    b4 -> out_of_bounds [label=\"13\"];
+}
+"
+        );
+
+        let mut report = Vec::new();
+        cfg.write_report(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "terminates: Loops
+unreachable: 2, 4
+loop: 0 -> 1 -> 3
+flip: 3 jmp -3 -> nop -3 (OutOfBounds)
+"
+        );
+    }
+}
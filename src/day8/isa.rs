@@ -0,0 +1,717 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use super::{tokenize, Error, Instruction, Program, Termination};
+
+/// Name of the register updated by the `acc` instruction.
+pub const ACCUMULATOR: &str = "acc";
+
+/// Instruction set versions, selected with an `.isa <version>` directive
+/// before the first instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    /// The original `nop`, `acc` and `jmp` with immediate operands. This is
+    /// the default, and programs run exactly as with [`super::Execution`].
+    #[default]
+    V1,
+    /// Named registers, arithmetic, conditional jumps, calls and I/O ports.
+    V2,
+}
+
+/// An immediate value, or the value of a register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Value(isize),
+    Register(String),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{:+}", value),
+            Self::Register(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// An instruction of the extended instruction set. Jump, call and port
+/// operands are relative offsets and port numbers respectively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Nop(Operand),
+    Acc(Operand),
+    Jump(Operand),
+    Set(String, Operand),
+    Add(String, Operand),
+    Mul(String, Operand),
+    Mod(String, Operand),
+    /// Jump by the second operand if the first is zero.
+    JumpIfZero(Operand, Operand),
+    /// Jump by the second operand if the first is greater than zero.
+    JumpIfPositive(Operand, Operand),
+    /// Push the return line and jump by the operand.
+    Call(Operand),
+    Ret,
+    /// Read a value from a port into a register.
+    In(String, Operand),
+    /// Write a value to a port.
+    Out(Operand, Operand),
+}
+
+impl Op {
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Self::Nop(_) => "nop",
+            Self::Acc(_) => "acc",
+            Self::Jump(_) => "jmp",
+            Self::Set(..) => "set",
+            Self::Add(..) => "add",
+            Self::Mul(..) => "mul",
+            Self::Mod(..) => "mod",
+            Self::JumpIfZero(..) => "jz",
+            Self::JumpIfPositive(..) => "jgz",
+            Self::Call(_) => "call",
+            Self::Ret => "ret",
+            Self::In(..) => "in",
+            Self::Out(..) => "out",
+        }
+    }
+
+    fn parse_line(version: Version, line: usize, source: &str) -> Result<Option<Self>, Error> {
+        let mut tokens = tokenize(source);
+        let (column, opcode) = match tokens.next() {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let args: Vec<_> = tokens.collect();
+
+        let arity = |n: usize| {
+            if let Some((column, token)) = args.get(n) {
+                return Err(Error::UnexpectedToken {
+                    line,
+                    column: *column,
+                    token: (*token).to_owned(),
+                });
+            }
+            if args.len() < n {
+                let (column, token) = args.last().copied().unwrap_or((column, opcode));
+                return Err(Error::MissingOperand {
+                    line,
+                    column: column + token.chars().count(),
+                });
+            }
+            Ok(())
+        };
+        let operand = |i: usize| parse_operand(version, line, args[i]);
+        let register = |i: usize| parse_register(line, args[i]);
+
+        let op = match (version, opcode) {
+            (_, "nop") => arity(1).and_then(|_| Ok(Self::Nop(operand(0)?))),
+            (_, "acc") => arity(1).and_then(|_| Ok(Self::Acc(operand(0)?))),
+            (_, "jmp") => arity(1).and_then(|_| Ok(Self::Jump(operand(0)?))),
+            (Version::V2, "call") => arity(1).and_then(|_| Ok(Self::Call(operand(0)?))),
+            (Version::V2, "ret") => arity(0).map(|_| Self::Ret),
+            (Version::V2, "set") => arity(2).and_then(|_| Ok(Self::Set(register(0)?, operand(1)?))),
+            (Version::V2, "add") => arity(2).and_then(|_| Ok(Self::Add(register(0)?, operand(1)?))),
+            (Version::V2, "mul") => arity(2).and_then(|_| Ok(Self::Mul(register(0)?, operand(1)?))),
+            (Version::V2, "mod") => arity(2).and_then(|_| Ok(Self::Mod(register(0)?, operand(1)?))),
+            (Version::V2, "in") => arity(2).and_then(|_| Ok(Self::In(register(0)?, operand(1)?))),
+            (Version::V2, "jz") => {
+                arity(2).and_then(|_| Ok(Self::JumpIfZero(operand(0)?, operand(1)?)))
+            }
+            (Version::V2, "jgz") => {
+                arity(2).and_then(|_| Ok(Self::JumpIfPositive(operand(0)?, operand(1)?)))
+            }
+            (Version::V2, "out") => arity(2).and_then(|_| Ok(Self::Out(operand(0)?, operand(1)?))),
+            _ => Err(Error::UnknownOpcode {
+                line,
+                column,
+                opcode: opcode.to_owned(),
+            }),
+        }?;
+
+        Ok(Some(op))
+    }
+}
+
+fn parse_register(line: usize, (column, token): (usize, &str)) -> Result<String, Error> {
+    let mut chars = token.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+
+    if valid {
+        Ok(token.to_owned())
+    } else {
+        Err(Error::InvalidOperand {
+            line,
+            column,
+            operand: token.to_owned(),
+        })
+    }
+}
+
+fn parse_operand(version: Version, line: usize, token: (usize, &str)) -> Result<Operand, Error> {
+    match (token.1.parse(), version) {
+        (Ok(value), _) => Ok(Operand::Value(value)),
+        (Err(_), Version::V2) => parse_register(line, token).map(Operand::Register),
+        (Err(_), Version::V1) => Err(Error::InvalidOperand {
+            line,
+            column: token.0,
+            operand: token.1.to_owned(),
+        }),
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode())?;
+        match self {
+            Self::Nop(a) | Self::Acc(a) | Self::Jump(a) | Self::Call(a) => write!(f, " {}", a),
+            Self::Set(r, a) | Self::Add(r, a) | Self::Mul(r, a) | Self::Mod(r, a) => {
+                write!(f, " {} {}", r, a)
+            }
+            Self::In(r, port) => write!(f, " {} {}", r, port),
+            Self::JumpIfZero(a, b) | Self::JumpIfPositive(a, b) | Self::Out(a, b) => {
+                write!(f, " {} {}", a, b)
+            }
+            Self::Ret => Ok(()),
+        }
+    }
+}
+
+impl From<Instruction> for Op {
+    fn from(inst: Instruction) -> Self {
+        match inst {
+            Instruction::Nop(arg) => Self::Nop(Operand::Value(arg)),
+            Instruction::Acc(arg) => Self::Acc(Operand::Value(arg)),
+            Instruction::Jump(arg) => Self::Jump(Operand::Value(arg)),
+        }
+    }
+}
+
+/// A program for a given version of the instruction set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IsaProgram {
+    version: Version,
+    ops: Vec<Op>,
+}
+
+impl IsaProgram {
+    pub fn new(version: Version, ops: Vec<Op>) -> Self {
+        Self { version, ops }
+    }
+
+    /// Assembles a program like [`Program::assemble`], accepting the
+    /// instructions of the version chosen by an optional `.isa` directive.
+    pub fn assemble(source: &str) -> Result<Self, Error> {
+        let mut version = None;
+        let mut ops = Vec::new();
+
+        for (line, text) in source.lines().enumerate().map(|(l, t)| (l + 1, t)) {
+            let mut tokens = tokenize(text);
+            if let Some((column, ".isa")) = tokens.next() {
+                if version.is_some() || !ops.is_empty() {
+                    return Err(Error::UnexpectedToken {
+                        line,
+                        column,
+                        token: ".isa".to_owned(),
+                    });
+                }
+                version = match tokens.next() {
+                    Some((_, "1")) => Some(Version::V1),
+                    Some((_, "2")) => Some(Version::V2),
+                    Some((column, v)) => {
+                        return Err(Error::UnsupportedVersion {
+                            line,
+                            column,
+                            version: v.to_owned(),
+                        })
+                    }
+                    None => {
+                        return Err(Error::MissingOperand {
+                            line,
+                            column: column + 4,
+                        })
+                    }
+                };
+                if let Some((column, token)) = tokens.next() {
+                    return Err(Error::UnexpectedToken {
+                        line,
+                        column,
+                        token: token.to_owned(),
+                    });
+                }
+                continue;
+            }
+
+            if let Some(op) = Op::parse_line(version.unwrap_or_default(), line, text)? {
+                ops.push(op);
+            }
+        }
+
+        Ok(Self {
+            version: version.unwrap_or_default(),
+            ops,
+        })
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl From<&Program> for IsaProgram {
+    fn from(program: &Program) -> Self {
+        Self {
+            version: Version::V1,
+            ops: program
+                .instructions()
+                .iter()
+                .map(|i| Op::from(*i))
+                .collect(),
+        }
+    }
+}
+
+impl Display for IsaProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version == Version::V2 {
+            writeln!(f, ".isa 2")?;
+        }
+        for op in self.ops.iter() {
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for IsaProgram {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::assemble(s)
+    }
+}
+
+/// Queues connecting a [`Machine`] to the caller, read by `in` and written
+/// by `out`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Port {
+    pub input: VecDeque<isize>,
+    pub output: VecDeque<isize>,
+}
+
+/// The state change an instruction makes, applied only once the instruction
+/// is known to succeed.
+enum Effect {
+    None,
+    Write(String, isize),
+    Push(usize),
+    Pop,
+    Read(String, usize),
+    Output(usize, isize),
+}
+
+/// Executes an [`IsaProgram`].
+///
+/// Version 1 programs stop as soon as a line is about to run a second time,
+/// like [`super::Execution`]. Version 2 programs may legitimately revisit
+/// lines, so only a step limit stops them from running forever.
+pub struct Machine<'a> {
+    program: &'a IsaProgram,
+    pc: usize,
+    registers: HashMap<String, isize>,
+    stack: Vec<usize>,
+    visited: HashSet<usize>,
+    steps: usize,
+    step_limit: Option<usize>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: &'a IsaProgram) -> Self {
+        Self {
+            program,
+            pc: 0,
+            registers: HashMap::new(),
+            stack: Vec::new(),
+            visited: HashSet::new(),
+            steps: 0,
+            step_limit: None,
+        }
+    }
+
+    /// Stops execution with [`Termination::StepLimitExceeded`] after `limit`
+    /// instructions.
+    pub fn with_step_limit(program: &'a IsaProgram, limit: usize) -> Self {
+        Self {
+            step_limit: Some(limit),
+            ..Self::new(program)
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Value of the register `name`, registers never written being `0`.
+    pub fn register(&self, name: &str) -> isize {
+        self.registers.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn set_register(&mut self, name: &str, value: isize) {
+        self.registers.insert(name.to_owned(), value);
+    }
+
+    pub fn accumulator(&self) -> isize {
+        self.register(ACCUMULATOR)
+    }
+
+    /// Return lines of the calls in progress, innermost last.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    fn value(&self, operand: &Operand) -> isize {
+        match operand {
+            Operand::Value(value) => *value,
+            Operand::Register(name) => self.register(name),
+        }
+    }
+
+    fn port(&self, operand: &Operand, ports: &[Port]) -> Result<usize, Termination> {
+        let port = self.value(operand);
+        if port >= 0 && (port as usize) < ports.len() {
+            Ok(port as usize)
+        } else {
+            Err(Termination::NoSuchPort { port })
+        }
+    }
+
+    pub fn run(&mut self, ports: &mut [Port]) -> Termination {
+        loop {
+            if let Some(termination) = self.step(ports) {
+                return termination;
+            }
+        }
+    }
+
+    /// Executes the next instruction, returning why execution stopped if it
+    /// cannot continue. A machine stopped by [`Termination::AwaitingInput`]
+    /// resumes once input has been queued.
+    pub fn step(&mut self, ports: &mut [Port]) -> Option<Termination> {
+        self.try_step(ports).err()
+    }
+
+    fn try_step(&mut self, ports: &mut [Port]) -> Result<(), Termination> {
+        let pc = self.pc;
+        if pc == self.program.len() {
+            return Err(Termination::Halted);
+        }
+        if matches!(self.step_limit, Some(limit) if self.steps >= limit) {
+            return Err(Termination::StepLimitExceeded);
+        }
+        if self.program.version == Version::V1 && self.visited.contains(&pc) {
+            return Err(Termination::LoopDetected {
+                pc,
+                acc: self.accumulator(),
+            });
+        }
+
+        let overflow = Termination::Overflow { pc };
+        let arithmetic = |register: &String, f: &dyn Fn(isize) -> Option<isize>| {
+            f(self.register(register))
+                .map(|value| Effect::Write(register.clone(), value))
+                .ok_or(overflow)
+        };
+        // A target past the range of `isize` is reported saturated.
+        let relative = |offset: isize| {
+            (pc as isize)
+                .checked_add(offset)
+                .ok_or(Termination::OutOfBounds {
+                    target: (pc as isize).saturating_add(offset),
+                })
+        };
+        let next = pc as isize + 1;
+
+        let (target, effect) = match &self.program.ops[pc] {
+            Op::Nop(_) => (next, Effect::None),
+            Op::Acc(a) => {
+                let a = self.value(a);
+                let reg = ACCUMULATOR.to_owned();
+                let effect = arithmetic(&reg, &|v| v.checked_add(a))
+                    .map_err(|_| Termination::AccumulatorOverflow { pc })?;
+                (next, effect)
+            }
+            Op::Jump(offset) => (relative(self.value(offset))?, Effect::None),
+            Op::Set(r, a) => (next, Effect::Write(r.clone(), self.value(a))),
+            Op::Add(r, a) => {
+                let a = self.value(a);
+                (next, arithmetic(r, &|v| v.checked_add(a))?)
+            }
+            Op::Mul(r, a) => {
+                let a = self.value(a);
+                (next, arithmetic(r, &|v| v.checked_mul(a))?)
+            }
+            Op::Mod(r, a) => {
+                let a = self.value(a);
+                if a == 0 {
+                    return Err(Termination::DivisionByZero { pc });
+                }
+                (next, arithmetic(r, &|v| v.checked_rem_euclid(a))?)
+            }
+            Op::JumpIfZero(a, offset) if self.value(a) == 0 => {
+                (relative(self.value(offset))?, Effect::None)
+            }
+            Op::JumpIfPositive(a, offset) if self.value(a) > 0 => {
+                (relative(self.value(offset))?, Effect::None)
+            }
+            Op::JumpIfZero(..) | Op::JumpIfPositive(..) => (next, Effect::None),
+            Op::Call(offset) => (relative(self.value(offset))?, Effect::Push(pc + 1)),
+            Op::Ret => match self.stack.last() {
+                Some(line) => (*line as isize, Effect::Pop),
+                None => return Err(Termination::StackUnderflow { pc }),
+            },
+            Op::In(r, port) => {
+                let port = self.port(port, ports)?;
+                if ports[port].input.is_empty() {
+                    return Err(Termination::AwaitingInput { port });
+                }
+                (next, Effect::Read(r.clone(), port))
+            }
+            Op::Out(a, port) => (next, Effect::Output(self.port(port, ports)?, self.value(a))),
+        };
+
+        if target < 0 || target as usize > self.program.len() {
+            return Err(Termination::OutOfBounds { target });
+        }
+
+        match effect {
+            Effect::None => {}
+            Effect::Write(register, value) => {
+                self.registers.insert(register, value);
+            }
+            Effect::Push(line) => self.stack.push(line),
+            Effect::Pop => {
+                self.stack.pop();
+            }
+            Effect::Read(register, port) => {
+                let value = ports[port].input.pop_front().unwrap();
+                self.registers.insert(register, value);
+            }
+            Effect::Output(port, value) => ports[port].output.push_back(value),
+        }
+
+        self.visited.insert(pc);
+        self.pc = target as usize;
+        self.steps += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::day8::Execution;
+
+    #[test]
+    fn version_1_unchanged() {
+        let source = "nop +0
+        acc +1
+        jmp +4
+        acc +3
+        jmp -3
+        acc -99
+        acc +1
+        jmp -4
+        acc +6";
+
+        let program = IsaProgram::assemble(source).unwrap();
+        assert_eq!(program.version(), Version::V1);
+        assert_eq!(
+            program,
+            IsaProgram::from(&Program::assemble(source).unwrap())
+        );
+        assert_eq!(
+            Machine::new(&program).run(&mut []),
+            Termination::LoopDetected { pc: 1, acc: 5 }
+        );
+
+        assert_eq!(
+            IsaProgram::assemble("set a +1"),
+            Err(Error::UnknownOpcode {
+                line: 1,
+                column: 1,
+                opcode: "set".to_owned()
+            })
+        );
+        assert_eq!(
+            IsaProgram::assemble(".isa 1\nacc a"),
+            Err(Error::InvalidOperand {
+                line: 2,
+                column: 5,
+                operand: "a".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn version_1_terminations_match_execution() {
+        let programs = [
+            "acc +1\nnop +0".to_owned(),
+            "acc +1\njmp -1".to_owned(),
+            "acc +2\njmp -3".to_owned(),
+            "jmp +0".to_owned(),
+            format!("nop +0\njmp +{}", isize::MAX),
+            format!("acc +{}\nacc +1", isize::MAX),
+            format!("acc {}\nacc -1\nnop +0", isize::MIN),
+        ];
+        for source in programs.iter() {
+            let program = Program::assemble(source).unwrap();
+            assert_eq!(
+                Machine::new(&IsaProgram::from(&program)).run(&mut []),
+                Execution::new().run(&program),
+                "{:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn factorial() {
+        let program = IsaProgram::assemble(
+            ".isa 2
+            in n 0
+            set r 1
+            jz n +4   ; done
+            mul r n
+            add n -1
+            jmp -3
+            out r 0",
+        )
+        .unwrap();
+
+        let mut ports = vec![Port::default()];
+        let mut machine = Machine::new(&program);
+        assert_eq!(
+            machine.run(&mut ports),
+            Termination::AwaitingInput { port: 0 }
+        );
+
+        ports[0].input.push_back(5);
+        assert_eq!(machine.run(&mut ports), Termination::Halted);
+        assert_eq!(ports[0].output, vec![120]);
+        assert_eq!(machine.register("n"), 0);
+    }
+
+    #[test]
+    fn call_and_return() {
+        let program = IsaProgram::assemble(
+            ".isa 2
+            set a 3
+            call +4
+            out a 1
+            acc a
+            jmp +4
+            mul a a   ; square
+            mod a 7
+            ret",
+        )
+        .unwrap();
+
+        let mut ports = vec![Port::default(), Port::default()];
+        let mut machine = Machine::new(&program);
+        assert_eq!(machine.run(&mut ports), Termination::Halted);
+        assert_eq!(ports[1].output, vec![2]);
+        assert_eq!(machine.accumulator(), 2);
+        assert!(machine.stack().is_empty());
+    }
+
+    #[test]
+    fn faults() {
+        let run = |source: &str| {
+            let program = IsaProgram::assemble(source).unwrap();
+            Machine::with_step_limit(&program, 100).run(&mut [Port::default()])
+        };
+
+        assert_eq!(run(".isa 2\nret"), Termination::StackUnderflow { pc: 0 });
+        assert_eq!(
+            run(".isa 2\nmod a 0"),
+            Termination::DivisionByZero { pc: 0 }
+        );
+        assert_eq!(run(".isa 2\nout 1 3"), Termination::NoSuchPort { port: 3 });
+        assert_eq!(
+            run(".isa 2\nset a 9223372036854775807\nadd a 1"),
+            Termination::Overflow { pc: 1 }
+        );
+        assert_eq!(
+            run(".isa 2\njgz 1 -1"),
+            Termination::OutOfBounds { target: -1 }
+        );
+        assert_eq!(run(".isa 2\njmp 0"), Termination::StepLimitExceeded);
+        for jump in ["jmp a", "jgz 1 a", "call a"].iter() {
+            assert_eq!(
+                run(&format!(".isa 2\nset a 9223372036854775807\n{}", jump)),
+                Termination::OutOfBounds { target: isize::MAX }
+            );
+        }
+    }
+
+    #[test]
+    fn assemble_errors() {
+        assert_eq!(
+            IsaProgram::assemble(".isa 3"),
+            Err(Error::UnsupportedVersion {
+                line: 1,
+                column: 6,
+                version: "3".to_owned()
+            })
+        );
+        assert_eq!(
+            IsaProgram::assemble("nop +0\n.isa 2"),
+            Err(Error::UnexpectedToken {
+                line: 2,
+                column: 1,
+                token: ".isa".to_owned()
+            })
+        );
+        assert_eq!(
+            IsaProgram::assemble(".isa 2\nset A 1"),
+            Err(Error::InvalidOperand {
+                line: 2,
+                column: 5,
+                operand: "A".to_owned()
+            })
+        );
+        assert_eq!(
+            IsaProgram::assemble(".isa 2\njz a"),
+            Err(Error::MissingOperand { line: 2, column: 5 })
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let source = ".isa 2
+in n +0
+jgz n +2
+ret
+call -2
+out acc +0
+";
+        let program = IsaProgram::assemble(source).unwrap();
+        assert_eq!(program.to_string(), source);
+        assert_eq!(IsaProgram::assemble(&program.to_string()), Ok(program));
+    }
+}
@@ -1,34 +1,322 @@
 use aoc_runner_derive::{aoc, aoc_generator};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{self, BufRead};
 use std::num::ParseIntError;
 
-pub fn decode_xmas(numbers: &[usize], preamble: usize) -> usize {
-    numbers
+use crate::params;
+
+/// Parameters of the XMAS cipher, settable from the runner with
+/// `--param preamble=<length>` and `--param distinct`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cipher {
+    pub preamble: usize,
+    /// Whether the two numbers summing to a valid number must have different
+    /// values, rather than only different positions.
+    pub distinct_values: bool,
+}
+
+impl Default for Cipher {
+    fn default() -> Self {
+        Self {
+            preamble: 25,
+            distinct_values: false,
+        }
+    }
+}
+
+impl Cipher {
+    pub fn from_params() -> Result<Self, params::Error> {
+        let default = Self::default();
+        Ok(Self {
+            preamble: params::get_or("preamble", default.preamble)?,
+            distinct_values: params::get_or("distinct", default.distinct_values)?,
+        })
+    }
+
+    pub fn validator(&self) -> XmasValidator {
+        XmasValidator::new(self.preamble).distinct_values(self.distinct_values)
+    }
+
+    /// The first number that is not the sum of two of the numbers before it.
+    pub fn first_invalid(&self, numbers: &[usize]) -> Option<usize> {
+        self.validator()
+            .validate(numbers.iter().copied())
+            .next()
+            .map(|invalid| invalid.value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Param(params::Error),
+    NoInvalidNumber,
+    NoWeakness(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Param(e) => write!(f, "{}", e),
+            Self::NoInvalidNumber => write!(f, "Every number is valid"),
+            Self::NoWeakness(n) => write!(f, "No contiguous set sums to {}", n),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<params::Error> for Error {
+    fn from(e: params::Error) -> Self {
+        Self::Param(e)
+    }
+}
+
+/// A number that is not the sum of two of the numbers before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Invalid {
+    /// Index of the number in the stream, counting the preamble.
+    pub position: usize,
+    pub value: usize,
+}
+
+/// Checks a stream of numbers one at a time against the `preamble` numbers
+/// before each of them.
+///
+/// The window is kept in a ring buffer along with a count of every sum of two
+/// numbers in it, so each number is checked in constant time and the index is
+/// updated in time linear in the preamble length.
+#[derive(Debug, Clone)]
+pub struct XmasValidator {
+    preamble: usize,
+    window: VecDeque<usize>,
+    sums: HashMap<usize, usize>,
+    position: usize,
+    distinct_values: bool,
+}
+
+impl XmasValidator {
+    pub fn new(preamble: usize) -> Self {
+        Self {
+            preamble,
+            window: VecDeque::with_capacity(preamble),
+            sums: HashMap::new(),
+            position: 0,
+            distinct_values: false,
+        }
+    }
+
+    /// Only counts sums of two numbers with different values. Must be set
+    /// before any number is pushed.
+    pub fn distinct_values(mut self, distinct: bool) -> Self {
+        self.distinct_values = distinct;
+        self
+    }
+
+    /// Whether `n` is the sum of two numbers, at different positions, in the
+    /// current window.
+    pub fn is_valid(&self, n: usize) -> bool {
+        self.sums.contains_key(&n)
+    }
+
+    /// Checks `n` against the window once the preamble has been read, then
+    /// adds it to the window.
+    pub fn push(&mut self, n: usize) -> Option<Invalid> {
+        let invalid = Some(Invalid {
+            position: self.position,
+            value: n,
+        })
+        .filter(|_| self.window.len() == self.preamble && !self.is_valid(n));
+
+        if self.window.len() == self.preamble {
+            if let Some(oldest) = self.window.pop_front() {
+                for sum in pair_sums(&self.window, oldest, self.distinct_values) {
+                    if let Entry::Occupied(mut count) = self.sums.entry(sum) {
+                        *count.get_mut() -= 1;
+                        if *count.get() == 0 {
+                            count.remove();
+                        }
+                    }
+                }
+            }
+        }
+        if self.preamble > 0 {
+            for sum in pair_sums(&self.window, n, self.distinct_values) {
+                *self.sums.entry(sum).or_default() += 1;
+            }
+            self.window.push_back(n);
+        }
+        self.position += 1;
+
+        invalid
+    }
+
+    /// Lazily yields every invalid number in `numbers`.
+    pub fn validate<I: IntoIterator<Item = usize>>(self, numbers: I) -> Invalids<I::IntoIter> {
+        Invalids {
+            validator: self,
+            numbers: numbers.into_iter(),
+        }
+    }
+
+    /// Lazily yields every invalid number in `reader`, one number per line.
+    /// Blank lines are skipped, and lines that are not numbers are reported
+    /// as [`io::ErrorKind::InvalidData`] errors.
+    pub fn validate_reader<R: BufRead>(self, reader: R) -> ReaderInvalids<R> {
+        ReaderInvalids {
+            validator: self,
+            lines: reader.lines(),
+            line: 0,
+        }
+    }
+}
+
+/// Sums of `n` with each number in `window`, skipping sums that overflow.
+fn pair_sums(
+    window: &VecDeque<usize>,
+    n: usize,
+    distinct_values: bool,
+) -> impl Iterator<Item = usize> + '_ {
+    window
         .iter()
-        .enumerate()
-        .skip(preamble)
-        .find(|(i, n)| find_numbers(&numbers[i - preamble..*i], n).is_none())
-        .map(|(_, n)| *n)
-        .expect("Failed to find a number!")
-}
-
-fn find_numbers(numbers: &[usize], n: &usize) -> Option<usize> {
-    for x in numbers.iter() {
-        if let Some(y) = n.checked_sub(*x) {
-            if numbers.contains(&y) {
-                return Some(*x);
+        .filter(move |&&x| !distinct_values || x != n)
+        .filter_map(move |x| x.checked_add(n))
+}
+
+/// Iterator returned by [`XmasValidator::validate`].
+pub struct Invalids<I> {
+    validator: XmasValidator,
+    numbers: I,
+}
+
+impl<I: Iterator<Item = usize>> Iterator for Invalids<I> {
+    type Item = Invalid;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let validator = &mut self.validator;
+        self.numbers.by_ref().find_map(|n| validator.push(n))
+    }
+}
+
+/// Iterator returned by [`XmasValidator::validate_reader`].
+pub struct ReaderInvalids<R> {
+    validator: XmasValidator,
+    lines: io::Lines<R>,
+    line: usize,
+}
+
+impl<R: BufRead> Iterator for ReaderInvalids<R> {
+    type Item = io::Result<Invalid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for line in self.lines.by_ref() {
+            self.line += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let n = match line.trim().parse() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: {}", self.line, e),
+                    )))
+                }
+            };
+            if let Some(invalid) = self.validator.push(n) {
+                return Some(Ok(invalid));
             }
         }
+        None
+    }
+}
+
+pub fn decode_xmas(numbers: &[usize], preamble: usize) -> usize {
+    Cipher {
+        preamble,
+        ..Cipher::default()
+    }
+    .first_invalid(numbers)
+    .expect("Failed to find a number!")
+}
+
+/// A contiguous run of at least two numbers summing to the invalid number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weakness<T> {
+    pub start: usize,
+    /// One past the index of the last number in the run.
+    pub end: usize,
+    /// Sum of the smallest and largest numbers in the run.
+    pub weakness: T,
+}
+
+fn weakness<T: Copy + Ord + std::ops::Add<Output = T>>(
+    numbers: &[T],
+    start: usize,
+    end: usize,
+) -> Weakness<T> {
+    let run = &numbers[start..end];
+    Weakness {
+        start,
+        end,
+        weakness: *run.iter().min().unwrap() + *run.iter().max().unwrap(),
+    }
+}
+
+/// Finds the contiguous run of any length summing to `n` that ends first,
+/// using a sliding window in linear time.
+pub fn encryption_weakness(numbers: &[usize], n: usize) -> Option<Weakness<usize>> {
+    let mut start = 0;
+    let mut sum = 0;
+    for (end, &x) in numbers.iter().enumerate() {
+        if x > n {
+            start = end + 1;
+            sum = 0;
+            continue;
+        }
+        // Shrinking the window before adding keeps `sum` at most `n`, so it
+        // cannot overflow.
+        while sum > n - x {
+            sum -= numbers[start];
+            start += 1;
+        }
+        sum += x;
+        if sum == n && end > start {
+            return Some(weakness(numbers, start, end + 1));
+        }
     }
     None
 }
 
-pub fn encryption_weakness(numbers: &[usize], n: usize) -> usize {
-    for i in 2..20 {
-        if let Some(w) = numbers.windows(i).find(|&w| w.iter().sum::<usize>() == n) {
-            return w.iter().min().unwrap() + w.iter().max().unwrap();
+/// Like [`encryption_weakness`], but allows negative numbers by looking up
+/// earlier prefix sums, in expected linear time. Gives up with `None` once a
+/// prefix sum overflows.
+pub fn encryption_weakness_signed(numbers: &[isize], n: isize) -> Option<Weakness<isize>> {
+    let mut first_prefix = HashMap::new();
+    let mut prefix = Vec::with_capacity(numbers.len() + 1);
+    prefix.push(0isize);
+    for (i, x) in numbers.iter().enumerate() {
+        prefix.push(prefix[i].checked_add(*x)?);
+        let end = i + 1;
+        if end < 2 {
+            continue;
+        }
+        first_prefix.entry(prefix[end - 2]).or_insert(end - 2);
+        // A difference outside the range of `isize` cannot be a prefix sum.
+        let wanted = match prefix[end].checked_sub(n) {
+            Some(wanted) => wanted,
+            None => continue,
+        };
+        if let Some(&start) = first_prefix.get(&wanted) {
+            return Some(weakness(numbers, start, end));
         }
     }
-    panic!("Failed to find contiguous set with sum: {:?}", n);
+    None
 }
 
 #[aoc_generator(day9)]
@@ -37,14 +325,18 @@ fn parse_input_day9(input: &str) -> Result<Vec<usize>, ParseIntError> {
 }
 
 #[aoc(day9, part1)]
-pub fn part1(numbers: &[usize]) -> usize {
-    decode_xmas(numbers, 25)
+pub fn part1(numbers: &[usize]) -> Result<usize, Error> {
+    Cipher::from_params()?
+        .first_invalid(numbers)
+        .ok_or(Error::NoInvalidNumber)
 }
 
 #[aoc(day9, part2)]
-pub fn part2(numbers: &[usize]) -> usize {
-    let n = decode_xmas(numbers, 25);
+pub fn part2(numbers: &[usize]) -> Result<usize, Error> {
+    let n = part1(numbers)?;
     encryption_weakness(numbers, n)
+        .map(|w| w.weakness)
+        .ok_or(Error::NoWeakness(n))
 }
 
 #[cfg(test)]
@@ -82,6 +374,191 @@ mod tests {
     fn sample2() {
         let parsed = parse_input_day9(SAMPLE).unwrap();
         let n = decode_xmas(&parsed, 5);
-        assert_eq!(encryption_weakness(&parsed, n), 62);
+        assert_eq!(
+            encryption_weakness(&parsed, n),
+            Some(Weakness {
+                start: 2,
+                end: 6,
+                weakness: 62
+            })
+        );
+    }
+
+    #[test]
+    fn streaming() {
+        let parsed = parse_input_day9(SAMPLE).unwrap();
+        let invalids: Vec<_> = XmasValidator::new(5).validate(parsed).collect();
+        assert_eq!(
+            invalids,
+            vec![Invalid {
+                position: 14,
+                value: 127
+            }]
+        );
+
+        let reader = io::Cursor::new("1\n2\n\n3\n10\n13\n100\n");
+        let invalids: Vec<_> = XmasValidator::new(2)
+            .validate_reader(reader)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            invalids,
+            vec![
+                Invalid {
+                    position: 3,
+                    value: 10
+                },
+                Invalid {
+                    position: 5,
+                    value: 100
+                }
+            ]
+        );
+
+        let mut errors = XmasValidator::new(2).validate_reader(io::Cursor::new("1\nx\n"));
+        let error = errors.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2: "));
+    }
+
+    #[test]
+    fn pairs_need_two_numbers() {
+        let mut validator = XmasValidator::new(2);
+        assert_eq!(validator.push(3), None);
+        assert_eq!(validator.push(4), None);
+        assert_eq!(
+            validator.push(6),
+            Some(Invalid {
+                position: 2,
+                value: 6
+            })
+        );
+        // The window is now 4, 6.
+        assert!(validator.is_valid(10));
+        assert!(!validator.is_valid(7));
+        assert!(!validator.is_valid(8));
+
+        let mut validator = XmasValidator::new(3);
+        for n in [4, 4, 1].iter() {
+            validator.push(*n);
+        }
+        assert!(validator.is_valid(8));
+        validator.push(8);
+        assert!(!validator.is_valid(8));
+        assert!(validator.is_valid(9));
+    }
+
+    #[test]
+    fn cipher_params() {
+        let parsed = parse_input_day9(SAMPLE).unwrap();
+        assert_eq!(part1(&parsed), Err(Error::NoInvalidNumber));
+
+        params::set("preamble", "5");
+        assert_eq!(part1(&parsed), Ok(127));
+        assert_eq!(part2(&parsed), Ok(62));
+
+        params::set("preamble", "five");
+        assert!(matches!(part1(&parsed), Err(Error::Param(_))));
+        params::clear();
+    }
+
+    #[test]
+    fn distinct_values() {
+        let numbers = [1, 2, 3, 3, 6];
+        assert_eq!(Cipher::default().first_invalid(&numbers), None);
+
+        let cipher = Cipher {
+            preamble: 3,
+            distinct_values: false,
+        };
+        assert_eq!(cipher.first_invalid(&numbers), None);
+
+        let cipher = Cipher {
+            distinct_values: true,
+            ..cipher
+        };
+        assert_eq!(cipher.first_invalid(&numbers), Some(6));
+    }
+
+    #[test]
+    fn long_runs() {
+        let numbers: Vec<usize> = (1..=40).collect();
+        let expected = Some(Weakness {
+            start: 2,
+            end: 27,
+            weakness: 30,
+        });
+        assert_eq!(encryption_weakness(&numbers, 375), expected);
+
+        let signed: Vec<isize> = numbers.iter().map(|&n| n as isize).collect();
+        assert_eq!(
+            encryption_weakness_signed(&signed, 375),
+            Some(Weakness {
+                start: 2,
+                end: 27,
+                weakness: 30
+            })
+        );
+    }
+
+    #[test]
+    fn no_weakness() {
+        assert_eq!(encryption_weakness(&[1, 127, 1], 127), None);
+        assert_eq!(encryption_weakness_signed(&[1, 127, 1], 127), None);
+
+        assert_eq!(encryption_weakness(&[usize::MAX, 1], 5), None);
+        assert_eq!(
+            encryption_weakness(&[usize::MAX, 2, 3, usize::MAX - 1, 1], usize::MAX),
+            Some(Weakness {
+                start: 3,
+                end: 5,
+                weakness: usize::MAX
+            })
+        );
+        assert_eq!(
+            encryption_weakness_signed(&[2, 3, isize::MAX, 1], 5),
+            Some(Weakness {
+                start: 0,
+                end: 2,
+                weakness: 5
+            })
+        );
+        assert_eq!(encryption_weakness_signed(&[isize::MAX, 1, 2], 3), None);
+        assert_eq!(
+            encryption_weakness_signed(&[isize::MIN, 1, 2], isize::MAX),
+            None
+        );
+        assert_eq!(encryption_weakness(&[], 0), None);
+        assert_eq!(
+            encryption_weakness(&[127, 0], 127),
+            Some(Weakness {
+                start: 0,
+                end: 2,
+                weakness: 127
+            })
+        );
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(
+            encryption_weakness_signed(&[5, -3, 10, -2, 4], 9),
+            Some(Weakness {
+                start: 1,
+                end: 5,
+                weakness: 7
+            })
+        );
+    }
+
+    #[test]
+    fn variants_agree() {
+        let parsed = parse_input_day9(SAMPLE).unwrap();
+        let signed: Vec<isize> = parsed.iter().map(|&n| n as isize).collect();
+        for n in 0..1000 {
+            let unsigned = encryption_weakness(&parsed, n).map(|w| (w.start, w.end));
+            let signed = encryption_weakness_signed(&signed, n as isize).map(|w| (w.start, w.end));
+            assert_eq!(unsigned, signed, "sum {}", n);
+        }
     }
 }
@@ -12,4 +12,8 @@ pub mod day9;
 pub mod day10;
 pub mod day11;
 
+pub mod automaton;
+pub mod params;
+pub mod records;
+
 aoc_lib!{ year = 2020 }
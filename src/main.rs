@@ -1,3 +1,145 @@
-use aoc_runner_derive::aoc_main;
+// Also brings `Factory` and its per day and part traits into scope.
+use advent_of_code_2020::*;
+use aoc_runner::{ArcStr, Runner};
+use std::error::Error;
+use std::time::Instant;
+use std::{env, fs, process};
 
-aoc_main! { lib = advent_of_code_2020 }
+type Solver = fn(ArcStr) -> Result<Box<dyn Runner>, Box<dyn Error>>;
+
+struct Day {
+    day: u32,
+    input: &'static str,
+    parts: &'static [Solver],
+}
+
+/// Every solved day, with its puzzle input and solvers in part order.
+/// `aoc_main!` generates this from the registered solvers, but cannot take
+/// command line arguments, so new days and parts must be added here.
+macro_rules! days {
+    ($($day:literal => [$($part:ident),*],)*) => {
+        &[$(Day {
+            day: $day,
+            input: include_str!(concat!("../input/2020/day", $day, ".txt")),
+            parts: &[$(Factory::$part),*],
+        },)*]
+    };
+}
+
+const DAYS: &[Day] = days! {
+    1 => [day1_part1, day1_part2],
+    2 => [day2_part1, day2_part2],
+    3 => [day3_part1, day3_part2],
+    4 => [day4_part1, day4_part2],
+    5 => [day5_part1, day5_part2],
+    6 => [day6_part1, day6_part2],
+    7 => [day7_part1, day7_part2],
+    8 => [day8_part1, day8_part2],
+    9 => [day9_part1, day9_part2],
+    10 => [day10_part1, day10_part2],
+    11 => [day11_part1, day11_part2],
+};
+
+const USAGE: &str = "Usage: advent-of-code-2020 [options]
+
+Options:
+  --day <day>          only run one day
+  --part <part>        only run one part
+  --input <path>       read the input of the selected day from a file
+  --param <key=value>  pass a parameter to the solvers, or set a flag with <key>";
+
+struct Options {
+    day: Option<u32>,
+    part: Option<usize>,
+    input: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        day: None,
+        part: None,
+        input: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--day" => {
+                let day = value()?;
+                options.day = Some(day.parse().map_err(|_| format!("Invalid day {:?}", day))?);
+            }
+            "--part" => {
+                let part = value()?;
+                options.part = Some(
+                    part.parse()
+                        .map_err(|_| format!("Invalid part {:?}", part))?,
+                );
+            }
+            "--input" => options.input = Some(value()?),
+            "--param" => params::set_arg(&value()?).map_err(|e| e.to_string())?,
+            _ => return Err(format!("Unknown argument {:?}", arg)),
+        }
+    }
+    if options.input.is_some() && options.day.is_none() {
+        return Err("--input needs a --day".to_owned());
+    }
+    Ok(options)
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    println!("Advent of code {}", YEAR);
+
+    for day in DAYS
+        .iter()
+        .filter(|d| options.day.is_none_or(|n| n == d.day))
+    {
+        let input = match &options.input {
+            Some(path) => fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }),
+            None => day.input.to_owned(),
+        };
+        let input = ArcStr::from(input.as_str());
+
+        for (i, solver) in day.parts.iter().enumerate() {
+            let part = i + 1;
+            if options.part.is_some_and(|p| p != part) {
+                continue;
+            }
+
+            let start_time = Instant::now();
+            match solver(input.clone()) {
+                Ok(runner) => {
+                    let inter_time = Instant::now();
+                    match runner.try_run() {
+                        Ok(result) => {
+                            let final_time = Instant::now();
+                            println!(
+                                "Day {} - Part {}: {}\n\tgenerator: {:?},\n\trunner: {:?}\n",
+                                day.day,
+                                part,
+                                result,
+                                inter_time - start_time,
+                                final_time - inter_time
+                            );
+                        }
+                        Err(e) => eprintln!(
+                            "Day {} - Part {}: FAILED while running:\n{:#?}\n",
+                            day.day, part, e
+                        ),
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Day {} - Part {}: FAILED while generating:\n{:#?}\n",
+                    day.day, part, e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Named parameters passed to solvers from the command line, such as
+//! `--param preamble=5`.
+//!
+//! Parameters are stored per thread: the runner sets them on the thread
+//! running the solvers, and tests setting parameters do not affect each other.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+thread_local! {
+    static PARAMS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A `key=value` argument had an empty key.
+    MissingKey(String),
+    /// A parameter value could not be parsed as the type the solver expects.
+    Invalid { key: String, value: String },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingKey(arg) => write!(f, "Expected key=value, found {:?}", arg),
+            Self::Invalid { key, value } => {
+                write!(f, "Invalid value {:?} for parameter {:?}", value, key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Sets a parameter from a `key=value` argument. A bare `key` sets the
+/// parameter to `true`, for flags.
+pub fn set_arg(arg: &str) -> Result<(), Error> {
+    let (key, value) = match arg.find('=') {
+        Some(i) => (&arg[..i], &arg[i + 1..]),
+        None => (arg, "true"),
+    };
+    if key.trim().is_empty() {
+        return Err(Error::MissingKey(arg.to_owned()));
+    }
+    set(key.trim(), value.trim());
+    Ok(())
+}
+
+pub fn set(key: &str, value: &str) {
+    PARAMS.with(|params| params.borrow_mut().insert(key.to_owned(), value.to_owned()));
+}
+
+pub fn clear() {
+    PARAMS.with(|params| params.borrow_mut().clear());
+}
+
+/// The raw value of a parameter, if it was set.
+pub fn raw(key: &str) -> Option<String> {
+    PARAMS.with(|params| params.borrow().get(key).cloned())
+}
+
+/// The value of a parameter parsed as `T`, or `default` if it was not set.
+pub fn get_or<T: FromStr>(key: &str, default: T) -> Result<T, Error> {
+    match raw(key) {
+        Some(value) => value.parse().map_err(|_| Error::Invalid {
+            key: key.to_owned(),
+            value,
+        }),
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args() {
+        assert_eq!(get_or("preamble", 25), Ok(25));
+
+        set_arg("preamble=5").unwrap();
+        set_arg("distinct").unwrap();
+        assert_eq!(get_or("preamble", 25), Ok(5));
+        assert_eq!(get_or("distinct", false), Ok(true));
+
+        set_arg("preamble = x").unwrap();
+        assert_eq!(
+            get_or("preamble", 25),
+            Err(Error::Invalid {
+                key: "preamble".to_owned(),
+                value: "x".to_owned()
+            })
+        );
+        assert_eq!(set_arg("=5"), Err(Error::MissingKey("=5".to_owned())));
+
+        clear();
+        assert_eq!(raw("preamble"), None);
+    }
+
+    #[test]
+    fn per_thread() {
+        set("preamble", "5");
+        let other = std::thread::spawn(|| raw("preamble")).join().unwrap();
+        assert_eq!(other, None);
+        assert_eq!(raw("preamble"), Some("5".to_owned()));
+    }
+}
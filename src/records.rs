@@ -0,0 +1,70 @@
+/// Iterator over records separated by one or more blank lines.
+///
+/// Lines containing only whitespace count as blank, both `\n` and `\r\n`
+/// line endings are accepted, and leading or trailing blank lines never
+/// produce empty records.
+pub struct Records<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = self.rest;
+        let mut start = None;
+        let mut end = 0;
+        let mut offset = 0;
+
+        for line in input.split_inclusive('\n') {
+            let line_start = offset;
+            offset += line.len();
+
+            if line.trim().is_empty() {
+                if start.is_some() {
+                    break;
+                }
+            } else {
+                start.get_or_insert(line_start);
+                end = line_start + line.trim_end_matches(&['\r', '\n'][..]).len();
+            }
+        }
+
+        self.rest = &input[offset..];
+        start.map(|start| &input[start..end])
+    }
+}
+
+/// Splits `input` into blank-line separated records.
+pub fn records(input: &str) -> Records<'_> {
+    Records { rest: input }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lf_separated() {
+        let parsed: Vec<_> = records("a\nb\n\nc\n").collect();
+        assert_eq!(parsed, vec!["a\nb", "c"]);
+    }
+
+    #[test]
+    fn crlf_separated() {
+        let parsed: Vec<_> = records("a\r\nb\r\n\r\nc\r\n").collect();
+        assert_eq!(parsed, vec!["a\r\nb", "c"]);
+    }
+
+    #[test]
+    fn whitespace_only_separator() {
+        let parsed: Vec<_> = records("\n  \na\n \t \n\n  b\n   ").collect();
+        assert_eq!(parsed, vec!["a", "  b"]);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(records("").count(), 0);
+        assert_eq!(records("\n \r\n").count(), 0);
+    }
+}